@@ -0,0 +1,76 @@
+//! Compares `WgslWorkspace::get_shader` with `WgslWorkspace::get_shader_into`
+//! rendering the example shaders every frame, run with `cargo bench`.
+
+#[path = "../tests/counting/mod.rs"]
+mod counting;
+
+use std::time::Instant;
+
+use counting::{allocations, CountingAllocator};
+use wgsl_plus::WgslWorkspace;
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+const FRAMES: usize = 10_000;
+const PATHS: [&str; 2] = ["my-shader.wgsl", "vertex.wgsl"];
+
+fn main() {
+    let mut workspace = WgslWorkspace::from_memory(
+        "shaders",
+        &[
+            (
+                "my-shader.wgsl",
+                include_str!("../examples/shaders/my-shader.wgsl"),
+            ),
+            (
+                "vertex.wgsl",
+                include_str!("../examples/shaders/vertex.wgsl"),
+            ),
+        ],
+    )
+    .unwrap();
+    workspace.set_global_bool("USE_TANGENTS", true);
+
+    let mut output = String::new();
+    for path in PATHS {
+        workspace.get_shader_into(path, &mut output).unwrap();
+    }
+
+    let plain = measure("get_shader", || {
+        for path in PATHS {
+            workspace.get_shader(path).unwrap();
+        }
+    });
+
+    let reused = measure("get_shader_into", || {
+        for path in PATHS {
+            workspace.get_shader_into(path, &mut output).unwrap();
+        }
+    });
+
+    println!(
+        "get_shader_into saves {} allocations per frame",
+        plain.saturating_sub(reused) as f64 / FRAMES as f64
+    );
+}
+
+/// Runs `frame` [`FRAMES`] times and prints the time and allocations per
+/// frame, returning the total number of allocations.
+fn measure(name: &str, mut frame: impl FnMut()) -> usize {
+    let start = Instant::now();
+    let count = allocations(|| {
+        for _ in 0..FRAMES {
+            frame();
+        }
+    });
+    let elapsed = start.elapsed();
+
+    println!(
+        "{name:>16}: {:>8.2?} and {:>5.2} allocations per frame",
+        elapsed / FRAMES as u32,
+        count as f64 / FRAMES as f64
+    );
+
+    count
+}
@@ -0,0 +1,28 @@
+use std::io::{self, BufRead, Write};
+
+use wgsl_plus::WgslWorkspaceState;
+
+fn main() {
+    let state = WgslWorkspaceState::default();
+    let stdin = io::stdin();
+
+    loop {
+        print!("> ");
+        io::stdout().flush().unwrap();
+
+        let mut line = String::new();
+        if stdin.lock().read_line(&mut line).unwrap() == 0 {
+            break;
+        }
+
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        match state.eval_expr(line) {
+            Ok(value) => println!("{value:?}"),
+            Err(error) => println!("error: {error:?}"),
+        }
+    }
+}
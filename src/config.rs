@@ -0,0 +1,496 @@
+//! Flattens serializable config structs into dotted globals for
+//! [`WgslWorkspace::set_globals_from`](crate::WgslWorkspace::set_globals_from).
+
+use std::fmt::{self, Display};
+
+use serde::{
+    ser::{
+        Impossible, SerializeMap, SerializeSeq, SerializeStruct, SerializeTuple,
+        SerializeTupleStruct,
+    },
+    Serialize, Serializer,
+};
+
+use crate::{expression::WgslLiteral, validate_identifier, WgslError};
+
+#[derive(Debug)]
+pub(crate) struct ConfigError(String);
+
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl serde::ser::Error for ConfigError {
+    fn custom<T: Display>(msg: T) -> Self {
+        ConfigError(msg.to_string())
+    }
+}
+
+/// Every leaf of `value` with its dotted key, e.g. `graphics.quality.level`.
+/// `None` leaves have no value, they unset their key.
+pub(crate) fn flatten<T: Serialize + ?Sized>(
+    prefix: &str,
+    value: &T,
+) -> Result<Vec<(String, Option<WgslLiteral>)>, WgslError> {
+    let mut globals = Vec::new();
+
+    value
+        .serialize(Flattener {
+            key: prefix.to_string(),
+            globals: &mut globals,
+        })
+        .map_err(|error| WgslError::UnsupportedValue(error.0))?;
+
+    Ok(globals)
+}
+
+/// Serializes a single value that can't have a name of its own, like a list
+/// element or a map key.
+fn literal<T: Serialize + ?Sized>(key: &str, value: &T) -> Result<WgslLiteral, ConfigError> {
+    let mut globals = Vec::new();
+
+    value.serialize(Flattener {
+        key: String::new(),
+        globals: &mut globals,
+    })?;
+
+    match globals.pop() {
+        Some((name, Some(literal))) if name.is_empty() && globals.is_empty() => Ok(literal),
+        _ => Err(ConfigError(format!(
+            "{key}: lists and map keys can only contain numbers, bools, strings and lists"
+        ))),
+    }
+}
+
+struct Flattener<'a> {
+    key: String,
+    globals: &'a mut Vec<(String, Option<WgslLiteral>)>,
+}
+
+impl Flattener<'_> {
+    fn push(self, literal: WgslLiteral) -> Result<(), ConfigError> {
+        self.globals.push((self.key, Some(literal)));
+
+        Ok(())
+    }
+
+    fn unsupported(self, what: &str) -> ConfigError {
+        ConfigError(format!("{}: {what} are not supported", self.key))
+    }
+}
+
+impl<'a> Serializer for Flattener<'a> {
+    type Ok = ();
+    type Error = ConfigError;
+
+    type SerializeSeq = ListFlattener<'a>;
+    type SerializeTuple = ListFlattener<'a>;
+    type SerializeTupleStruct = ListFlattener<'a>;
+    type SerializeTupleVariant = Impossible<(), ConfigError>;
+    type SerializeMap = StructFlattener<'a>;
+    type SerializeStruct = StructFlattener<'a>;
+    type SerializeStructVariant = Impossible<(), ConfigError>;
+
+    fn serialize_bool(self, v: bool) -> Result<(), ConfigError> {
+        self.push(WgslLiteral::Bool(v))
+    }
+
+    fn serialize_i8(self, v: i8) -> Result<(), ConfigError> {
+        self.serialize_i64(v.into())
+    }
+
+    fn serialize_i16(self, v: i16) -> Result<(), ConfigError> {
+        self.serialize_i64(v.into())
+    }
+
+    fn serialize_i32(self, v: i32) -> Result<(), ConfigError> {
+        self.serialize_i64(v.into())
+    }
+
+    fn serialize_i64(self, v: i64) -> Result<(), ConfigError> {
+        self.push(WgslLiteral::Integer(v))
+    }
+
+    fn serialize_u8(self, v: u8) -> Result<(), ConfigError> {
+        self.serialize_i64(v.into())
+    }
+
+    fn serialize_u16(self, v: u16) -> Result<(), ConfigError> {
+        self.serialize_i64(v.into())
+    }
+
+    fn serialize_u32(self, v: u32) -> Result<(), ConfigError> {
+        self.serialize_i64(v.into())
+    }
+
+    /// Stored as a bit pattern, like [`WgslWorkspace::set_global_u64`](crate::WgslWorkspace::set_global_u64).
+    fn serialize_u64(self, v: u64) -> Result<(), ConfigError> {
+        self.serialize_i64(v as i64)
+    }
+
+    fn serialize_f32(self, v: f32) -> Result<(), ConfigError> {
+        self.serialize_f64(v.into())
+    }
+
+    fn serialize_f64(self, v: f64) -> Result<(), ConfigError> {
+        self.push(WgslLiteral::Float(v))
+    }
+
+    fn serialize_char(self, v: char) -> Result<(), ConfigError> {
+        self.push(WgslLiteral::Str(v.to_string()))
+    }
+
+    fn serialize_str(self, v: &str) -> Result<(), ConfigError> {
+        self.push(WgslLiteral::Str(v.to_string()))
+    }
+
+    fn serialize_bytes(self, _v: &[u8]) -> Result<(), ConfigError> {
+        Err(self.unsupported("bytes"))
+    }
+
+    /// `None` fields are unset, like a variable that was never set.
+    fn serialize_none(self) -> Result<(), ConfigError> {
+        self.globals.push((self.key, None));
+
+        Ok(())
+    }
+
+    fn serialize_some<T: Serialize + ?Sized>(self, value: &T) -> Result<(), ConfigError> {
+        value.serialize(self)
+    }
+
+    fn serialize_unit(self) -> Result<(), ConfigError> {
+        Err(self.unsupported("unit values"))
+    }
+
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<(), ConfigError> {
+        Err(self.unsupported("unit structs"))
+    }
+
+    /// Enums without data are their variant name, `QUALITY == "High"`.
+    fn serialize_unit_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+    ) -> Result<(), ConfigError> {
+        self.serialize_str(variant)
+    }
+
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        value: &T,
+    ) -> Result<(), ConfigError> {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        _variant: &'static str,
+        _value: &T,
+    ) -> Result<(), ConfigError> {
+        Err(self.unsupported("enums with data"))
+    }
+
+    fn serialize_seq(self, len: Option<usize>) -> Result<ListFlattener<'a>, ConfigError> {
+        Ok(ListFlattener {
+            key: self.key,
+            globals: self.globals,
+            items: Vec::with_capacity(len.unwrap_or(0)),
+        })
+    }
+
+    fn serialize_tuple(self, len: usize) -> Result<ListFlattener<'a>, ConfigError> {
+        self.serialize_seq(Some(len))
+    }
+
+    fn serialize_tuple_struct(
+        self,
+        _name: &'static str,
+        len: usize,
+    ) -> Result<ListFlattener<'a>, ConfigError> {
+        self.serialize_seq(Some(len))
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        _variant: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeTupleVariant, ConfigError> {
+        Err(self.unsupported("enums with data"))
+    }
+
+    fn serialize_map(self, _len: Option<usize>) -> Result<StructFlattener<'a>, ConfigError> {
+        Ok(StructFlattener {
+            key: self.key,
+            globals: self.globals,
+            field: None,
+        })
+    }
+
+    fn serialize_struct(
+        self,
+        _name: &'static str,
+        len: usize,
+    ) -> Result<StructFlattener<'a>, ConfigError> {
+        self.serialize_map(Some(len))
+    }
+
+    fn serialize_struct_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        _variant: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeStructVariant, ConfigError> {
+        Err(self.unsupported("enums with data"))
+    }
+}
+
+/// Collects a sequence into a single [`WgslLiteral::List`].
+struct ListFlattener<'a> {
+    key: String,
+    globals: &'a mut Vec<(String, Option<WgslLiteral>)>,
+    items: Vec<WgslLiteral>,
+}
+
+impl SerializeSeq for ListFlattener<'_> {
+    type Ok = ();
+    type Error = ConfigError;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), ConfigError> {
+        self.items.push(literal(&self.key, value)?);
+
+        Ok(())
+    }
+
+    fn end(self) -> Result<(), ConfigError> {
+        self.globals
+            .push((self.key, Some(WgslLiteral::List(self.items))));
+
+        Ok(())
+    }
+}
+
+impl SerializeTuple for ListFlattener<'_> {
+    type Ok = ();
+    type Error = ConfigError;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), ConfigError> {
+        SerializeSeq::serialize_element(self, value)
+    }
+
+    fn end(self) -> Result<(), ConfigError> {
+        SerializeSeq::end(self)
+    }
+}
+
+impl SerializeTupleStruct for ListFlattener<'_> {
+    type Ok = ();
+    type Error = ConfigError;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), ConfigError> {
+        SerializeSeq::serialize_element(self, value)
+    }
+
+    fn end(self) -> Result<(), ConfigError> {
+        SerializeSeq::end(self)
+    }
+}
+
+/// Recurses into the fields of a struct or the entries of a map with string
+/// keys, `key.field` names each of them.
+struct StructFlattener<'a> {
+    key: String,
+    globals: &'a mut Vec<(String, Option<WgslLiteral>)>,
+    field: Option<String>,
+}
+
+impl StructFlattener<'_> {
+    fn field<T: Serialize + ?Sized>(&mut self, name: &str, value: &T) -> Result<(), ConfigError> {
+        // Map keys can be any string, but only identifiers can be referenced
+        validate_identifier(name).map_err(|_| {
+            ConfigError(format!("{}: `{name}` is not a valid identifier", self.key))
+        })?;
+
+        let key = if self.key.is_empty() {
+            name.to_string()
+        } else {
+            format!("{}.{name}", self.key)
+        };
+
+        value.serialize(Flattener {
+            key,
+            globals: self.globals,
+        })
+    }
+}
+
+impl SerializeMap for StructFlattener<'_> {
+    type Ok = ();
+    type Error = ConfigError;
+
+    fn serialize_key<T: Serialize + ?Sized>(&mut self, key: &T) -> Result<(), ConfigError> {
+        match literal(&self.key, key)? {
+            WgslLiteral::Str(key) => self.field = Some(key),
+            _ => {
+                return Err(ConfigError(format!(
+                    "{}: map keys must be strings",
+                    self.key
+                )))
+            }
+        }
+
+        Ok(())
+    }
+
+    fn serialize_value<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), ConfigError> {
+        let name = self.field.take().unwrap_or_default();
+
+        self.field(&name, value)
+    }
+
+    fn end(self) -> Result<(), ConfigError> {
+        Ok(())
+    }
+}
+
+impl SerializeStruct for StructFlattener<'_> {
+    type Ok = ();
+    type Error = ConfigError;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<(), ConfigError> {
+        self.field(key, value)
+    }
+
+    fn end(self) -> Result<(), ConfigError> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use serde::Serialize;
+
+    use crate::{WgslError, WgslWorkspace};
+
+    #[derive(Serialize)]
+    struct Graphics {
+        quality: Quality,
+        shadows: bool,
+    }
+
+    #[derive(Serialize)]
+    struct Quality {
+        level: i64,
+        scale: f64,
+    }
+
+    #[test]
+    fn nested_structs_are_flattened() {
+        let mut workspace = WgslWorkspace::from_memory(
+            "",
+            &[(
+                "a.wgsl",
+                "//: if graphics.quality.level >= 2 && graphics.shadows\nhigh\n//: else\nlow\n//: end",
+            )],
+        )
+        .unwrap();
+
+        let mut graphics = Graphics {
+            quality: Quality {
+                level: 3,
+                scale: 1.5,
+            },
+            shadows: true,
+        };
+
+        workspace.set_globals_from("graphics", &graphics).unwrap();
+        assert_eq!(workspace.get_shader("a.wgsl").unwrap(), "high\n");
+
+        graphics.quality.level = 1;
+        workspace.set_globals_from("graphics", &graphics).unwrap();
+        assert_eq!(workspace.get_shader("a.wgsl").unwrap(), "low\n");
+    }
+
+    #[test]
+    fn map_keys_must_be_identifiers() {
+        let mut workspace = WgslWorkspace::from_memory("", &[]).unwrap();
+
+        for key in ["my key", "1x", ""] {
+            let map = HashMap::from([(key, 1)]);
+
+            assert!(matches!(
+                workspace.set_globals_from("config", &map),
+                Err(WgslError::UnsupportedValue(_))
+            ));
+        }
+
+        let map = HashMap::from([("my_key", 1)]);
+        workspace.set_globals_from("config", &map).unwrap();
+    }
+
+    #[derive(Serialize)]
+    struct Optional {
+        x: Option<i64>,
+    }
+
+    #[test]
+    fn removed_values_are_unset() {
+        let mut workspace = WgslWorkspace::from_memory(
+            "",
+            &[(
+                "a.wgsl",
+                "//: if graphics.x
+x
+//: end",
+            )],
+        )
+        .unwrap();
+
+        workspace
+            .set_globals_from("graphics", &Optional { x: Some(3) })
+            .unwrap();
+        assert_eq!(workspace.get_shader("a.wgsl").unwrap(), "x\n");
+
+        workspace
+            .set_globals_from("graphics", &Optional { x: None })
+            .unwrap();
+        let error = workspace.get_shader("a.wgsl").unwrap_err();
+        assert!(matches!(error, WgslError::UndefinedVariable), "{error:?}");
+
+        workspace
+            .set_globals_from("config", &HashMap::from([("a", 1), ("b", 2)]))
+            .unwrap();
+        workspace
+            .set_globals_from("config", &HashMap::from([("a", 1)]))
+            .unwrap();
+        workspace.set_global_i64("configuration", 1);
+
+        let globals = &workspace.state().global_variables;
+        assert!(globals.contains_key("config.a"));
+        assert!(!globals.contains_key("config.b"));
+        assert!(globals.contains_key("configuration"));
+
+        workspace.set_global_i64("x", 1);
+        workspace
+            .set_globals_from("", &Optional { x: None })
+            .unwrap();
+        assert!(!workspace.state().global_variables.contains_key("x"));
+    }
+}
@@ -1,13 +1,262 @@
-use crate::{WgslError, WgslWorkspaceState};
+use std::{cmp::Ordering, str::Chars};
 
-#[derive(Debug, Clone, Copy, PartialEq, PartialOrd)]
+use crate::{WgslError, WgslWarning, WgslWorkspaceState, MAX_IDENTIFIER_LENGTH};
+
+/// Floats are compared like `f64`, so `0.0 == -0.0` and `NaN != NaN`. This
+/// is also how `==` behaves in expressions.
+///
+/// Not `Copy` since lists and strings were added, literals have to be cloned
+/// to be used twice.
+#[derive(Debug, Clone, PartialEq, PartialOrd)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum WgslLiteral {
     Integer(i64),
     Float(f64),
     Bool(bool),
+    List(Vec<WgslLiteral>),
+    /// Only usable inside the preprocessor, WGSL has no strings
+    Str(String),
+}
+
+impl WgslLiteral {
+    /// Formats the literal as WGSL source, floats always get a decimal point
+    /// and lists become `array(...)`. Strings are written quoted even though
+    /// WGSL can't use them.
+    pub fn to_wgsl_string(&self) -> String {
+        self.to_wgsl_string_with_precision(None)
+    }
+
+    /// Same as [`WgslLiteral::to_wgsl_string`], but floats have exactly
+    /// `precision` digits after the decimal point when it is set. `0` still
+    /// writes `.0` so the literal stays a float.
+    pub(crate) fn to_wgsl_string_with_precision(&self, precision: Option<usize>) -> String {
+        match self {
+            WgslLiteral::Integer(i) => i.to_string(),
+            WgslLiteral::Float(f) => match precision {
+                Some(precision) => fixed_float_to_string(*f, precision),
+                None => float_to_string(*f),
+            },
+            WgslLiteral::Bool(b) => b.to_string(),
+            WgslLiteral::List(items) => {
+                let items = items
+                    .iter()
+                    .map(|item| item.to_wgsl_string_with_precision(precision))
+                    .collect::<Vec<_>>();
+
+                format!("array({})", items.join(", "))
+            }
+            WgslLiteral::Str(s) => string_to_source(s),
+        }
+    }
+
+    /// Whether the literal counts as true in a condition. Numbers are true
+    /// when they are not exactly zero, so `-0.0` is false and `1e-20` is
+    /// true. NaN is an error, lists and strings can't be conditions.
+    pub fn is_truthy(&self) -> Result<bool, WgslError> {
+        match self {
+            WgslLiteral::Integer(i) => Ok(*i != 0),
+            WgslLiteral::Float(f) if f.is_nan() => Err(WgslError::NanCondition),
+            WgslLiteral::Float(f) => Ok(*f != 0.0),
+            WgslLiteral::Bool(b) => Ok(*b),
+            WgslLiteral::List(_) | WgslLiteral::Str(_) => Err(WgslError::InvalidExpression),
+        }
+    }
+
+    /// Returns `false` if the literal is or contains a NaN or infinite float.
+    pub fn is_finite(&self) -> bool {
+        match self {
+            WgslLiteral::Float(f) => f.is_finite(),
+            WgslLiteral::List(items) => items.iter().all(WgslLiteral::is_finite),
+            _ => true,
+        }
+    }
+
+    /// Returns `true` if the literal is or contains a float that is not zero
+    /// but is written as zero with `precision` digits after the decimal
+    /// point.
+    pub(crate) fn rounds_to_zero(&self, precision: usize) -> bool {
+        match self {
+            WgslLiteral::Float(f) => {
+                *f != 0.0 && fixed_float_to_string(*f, precision).parse() == Ok(0.0)
+            }
+            WgslLiteral::List(items) => items.iter().any(|item| item.rounds_to_zero(precision)),
+            _ => false,
+        }
+    }
+
+    /// Returns `true` if the literal is or contains a string.
+    pub fn contains_string(&self) -> bool {
+        match self {
+            WgslLiteral::Str(_) => true,
+            WgslLiteral::List(items) => items.iter().any(WgslLiteral::contains_string),
+            _ => false,
+        }
+    }
+}
+
+// Literals of a different type are never equal to a primitive
+
+impl PartialEq<i64> for WgslLiteral {
+    fn eq(&self, other: &i64) -> bool {
+        matches!(self, WgslLiteral::Integer(i) if i == other)
+    }
+}
+
+impl PartialEq<f64> for WgslLiteral {
+    fn eq(&self, other: &f64) -> bool {
+        matches!(self, WgslLiteral::Float(f) if f == other)
+    }
+}
+
+impl PartialEq<bool> for WgslLiteral {
+    fn eq(&self, other: &bool) -> bool {
+        matches!(self, WgslLiteral::Bool(b) if b == other)
+    }
+}
+
+impl PartialEq<&str> for WgslLiteral {
+    fn eq(&self, other: &&str) -> bool {
+        matches!(self, WgslLiteral::Str(s) if s == other)
+    }
+}
+
+/// The type a variable probably has, guessed from how it is used, see
+/// [`WgslShader::required_globals`](crate::WgslShader::required_globals).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WgslInferredType {
+    /// Used as a condition, e.g. `//: if X` or `X && Y`
+    Bool,
+    /// Used where only integers work, e.g. `X << 2` or `0..X`
+    Integer,
+    /// Used in arithmetic or ordering comparisons, e.g. `X * 2` or `X > 4`
+    Number,
+    List,
+    Str,
+    /// Only used where anything works, e.g. `//: const X`
+    Unknown,
+}
+
+impl WgslInferredType {
+    /// Combines the types of two uses of the same variable. Numbers are true
+    /// when they are not zero, so a variable used both as a condition and
+    /// as a number is a number.
+    pub fn merge(self, other: Self) -> Self {
+        match (self, other) {
+            (a, b) if a == b => a,
+            (Self::Unknown, other) | (other, Self::Unknown) => other,
+            (Self::Integer, Self::Number | Self::Bool)
+            | (Self::Number | Self::Bool, Self::Integer) => Self::Integer,
+            (Self::Number, Self::Bool) | (Self::Bool, Self::Number) => Self::Number,
+            _ => Self::Unknown,
+        }
+    }
+
+    fn of_literal(literal: &WgslLiteral) -> Self {
+        match literal {
+            WgslLiteral::Integer(_) | WgslLiteral::Float(_) => Self::Number,
+            WgslLiteral::Bool(_) => Self::Bool,
+            WgslLiteral::List(_) => Self::List,
+            WgslLiteral::Str(_) => Self::Str,
+        }
+    }
+}
+
+/// The type of a literal, used by [`WgslExpression::typecheck`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum StaticType {
+    Integer,
+    Float,
+    Bool,
+    List,
+    Str,
+}
+
+/// The WGSL type suffix of a substitution, `${X:f}` writes `X` as `1.0f`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum WgslSuffix {
+    /// `f`, integers are converted to floats
+    Float,
+    /// `i`
+    Signed,
+    /// `u`, negative integers are an error
+    Unsigned,
+}
+
+impl WgslSuffix {
+    pub fn new(source: &str) -> Result<Self, WgslError> {
+        match source.trim() {
+            "f" => Ok(Self::Float),
+            "i" => Ok(Self::Signed),
+            "u" => Ok(Self::Unsigned),
+            other => Err(WgslError::InvalidSuffix(other.to_string())),
+        }
+    }
+
+    pub fn symbol(&self) -> &'static str {
+        match self {
+            Self::Float => "f",
+            Self::Signed => "i",
+            Self::Unsigned => "u",
+        }
+    }
+
+    /// Writes `literal` as a WGSL literal with this suffix.
+    pub fn format(&self, literal: &WgslLiteral) -> Result<String, WgslError> {
+        let value = match (self, literal) {
+            (Self::Float, WgslLiteral::Integer(i)) => float_to_string(*i as f64),
+            (Self::Float, WgslLiteral::Float(f)) if f.is_finite() => float_to_string(*f),
+            (Self::Float, WgslLiteral::Float(_)) => {
+                Err(WgslError::NonFiniteFloat(literal.clone()))?
+            }
+            (Self::Signed, WgslLiteral::Integer(i)) => i.to_string(),
+            (Self::Unsigned, WgslLiteral::Integer(i)) if *i >= 0 => i.to_string(),
+            _ => Err(WgslError::SuffixMismatch {
+                suffix: *self,
+                value: literal.clone(),
+            })?,
+        };
+
+        Ok(format!("{value}{}", self.symbol()))
+    }
 }
 
-#[derive(Debug, Clone, Copy)]
+/// How integer arithmetic in expressions handles overflow.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum IntOverflow {
+    Wrapping,
+    /// Overflow results in [`WgslError::ArithmeticOverflow`]
+    #[default]
+    Checked,
+    Saturating,
+}
+
+impl IntOverflow {
+    fn apply(
+        self,
+        left: i64,
+        right: i64,
+        checked: fn(i64, i64) -> Option<i64>,
+        wrapping: fn(i64, i64) -> i64,
+        saturating: fn(i64, i64) -> i64,
+    ) -> Result<WgslLiteral, WgslError> {
+        match self {
+            Self::Wrapping => Ok(WgslLiteral::Integer(wrapping(left, right))),
+            Self::Checked => checked(left, right)
+                .map(WgslLiteral::Integer)
+                .ok_or(WgslError::ArithmeticOverflow),
+            Self::Saturating => Ok(WgslLiteral::Integer(saturating(left, right))),
+        }
+    }
+}
+
+/// A function callable from expressions, e.g. `clamp(x, 0, 64)`. The
+/// function is responsible for checking its own arguments.
+pub type WgslFunction = fn(&[WgslLiteral]) -> Result<WgslLiteral, WgslError>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum WgslOperator {
     Add,
     Subtract,
@@ -15,29 +264,57 @@ pub enum WgslOperator {
     Divide,
     BitwiseAnd,
     BitwiseOr,
+    ShiftLeft,
+    /// Keeps the sign like in Rust, `-8 >> 1` is `-4`
+    ShiftRight,
 }
 
 impl WgslOperator {
+    /// Higher binds tighter, the same as in Rust
     fn priority(&self) -> usize {
         match self {
-            Self::Add => 0,
-            Self::Subtract => 1,
-            Self::Multiply => 2,
-            Self::Divide => 3,
+            Self::Multiply | Self::Divide => 7,
+            Self::Add | Self::Subtract => 6,
+            Self::ShiftLeft | Self::ShiftRight => 5,
             Self::BitwiseAnd => 4,
-            Self::BitwiseOr => 5,
+            Self::BitwiseOr => 3,
+        }
+    }
+
+    pub fn symbol(&self) -> &'static str {
+        match self {
+            Self::Add => "+",
+            Self::Subtract => "-",
+            Self::Multiply => "*",
+            Self::Divide => "/",
+            Self::BitwiseAnd => "&",
+            Self::BitwiseOr => "|",
+            Self::ShiftLeft => "<<",
+            Self::ShiftRight => ">>",
         }
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum WgslUnaryOperator {
     Negate,
     Not,
     BitwiseNot,
 }
 
-#[derive(Debug, Clone, Copy)]
+impl WgslUnaryOperator {
+    pub fn symbol(&self) -> &'static str {
+        match self {
+            Self::Negate => "-",
+            Self::Not => "!",
+            Self::BitwiseNot => "~",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum WgslComparison {
     Equal,
     NotEqual,
@@ -49,9 +326,74 @@ pub enum WgslComparison {
     Or,
 }
 
-#[derive(Debug, Clone)]
+impl WgslComparison {
+    /// Higher binds tighter, always lower than [`WgslOperator`]s
+    fn priority(&self) -> usize {
+        match self {
+            Self::Equal
+            | Self::NotEqual
+            | Self::LessThan
+            | Self::LessThanOrEqual
+            | Self::GreaterThan
+            | Self::GreaterThanOrEqual => 2,
+            Self::And => 1,
+            Self::Or => 0,
+        }
+    }
+
+    pub fn symbol(&self) -> &'static str {
+        match self {
+            Self::Equal => "==",
+            Self::NotEqual => "!=",
+            Self::LessThan => "<",
+            Self::LessThanOrEqual => "<=",
+            Self::GreaterThan => ">",
+            Self::GreaterThanOrEqual => ">=",
+            Self::And => "&&",
+            Self::Or => "||",
+        }
+    }
+}
+
+/// Either kind of binary operator, only used while reordering
+#[derive(Clone, Copy)]
+enum WgslBinaryOperator {
+    Operator(WgslOperator),
+    Comparison(WgslComparison),
+}
+
+impl WgslBinaryOperator {
+    fn priority(&self) -> usize {
+        match self {
+            Self::Operator(operator) => operator.priority(),
+            Self::Comparison(comparison) => comparison.priority(),
+        }
+    }
+
+    fn join(self, left: WgslExpression, right: WgslExpression) -> WgslExpression {
+        let (left, right) = (Box::new(left), Box::new(right));
+
+        match self {
+            Self::Operator(operator) => WgslExpression::Operator {
+                left,
+                operator,
+                right,
+            },
+            Self::Comparison(comparison) => WgslExpression::Comparison {
+                left,
+                comparison,
+                right,
+            },
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum WgslExpression {
     Literal(WgslLiteral),
+    /// A variable, functions and variables have separate names so `max` can
+    /// be a variable while `max(a, b)` still calls the function.
     Reference(String),
     Operator {
         left: Box<WgslExpression>,
@@ -68,39 +410,233 @@ pub enum WgslExpression {
         right: Box<WgslExpression>,
     },
     Parenthesized(Box<WgslExpression>),
+    List(Vec<WgslExpression>),
+    /// A function call, only an identifier directly followed by `(` is a
+    /// function call.
+    Call {
+        function: String,
+        arguments: Vec<WgslExpression>,
+    },
+    /// `condition ? if_true : if_false`, only the selected side is evaluated
+    Select {
+        condition: Box<WgslExpression>,
+        if_true: Box<WgslExpression>,
+        if_false: Box<WgslExpression>,
+    },
 }
 
 impl WgslExpression {
     pub fn new(source: &str) -> Result<Self, WgslError> {
-        let mut chars = source.trim().chars().filter(|c| !c.is_whitespace());
-        let mut output =
-            Self::from_chars(&mut chars, false).map(|r| r.ok_or(WgslError::NoExpression))??;
+        let code = strip_comment(source);
+        let mut chars = code.chars();
+
+        let syntax_error = |chars: &Chars, error| {
+            let offset = code.len() - chars.as_str().len();
+            let column = code[..offset].chars().count();
+
+            WgslError::InvalidSyntax {
+                offset,
+                snippet: format!("{source}\n{}^", " ".repeat(column)),
+                error: Box::new(error),
+            }
+        };
+
+        let mut output = match Self::select_from_chars(&mut chars) {
+            Ok(Some(output)) => output,
+            Ok(None) => Err(syntax_error(&chars, WgslError::NoExpression))?,
+            Err(error) => Err(syntax_error(&chars, error))?,
+        };
 
         output.reorder();
 
-        if chars.clone().next().is_some() {
-            Err(WgslError::LeftoverChars(chars.collect()))?
-        } else {
-            Ok(output)
+        skip_whitespace(&mut chars);
+
+        let leftover = chars.as_str();
+
+        if leftover.is_empty() {
+            return Ok(output);
         }
+
+        // Another operand right after a complete expression, most likely a
+        // missing operator like `&&` in `//: if A B`
+        let mut next = chars.clone();
+        let error = match Self::from_chars(&mut next, true) {
+            Ok(Some(_)) => {
+                let found = &leftover[..leftover.len() - next.as_str().len()];
+                WgslError::UnexpectedToken(found.trim_end().to_string())
+            }
+            _ => WgslError::LeftoverChars(leftover.to_string()),
+        };
+
+        Err(syntax_error(&chars, error))
+    }
+
+    pub fn literal(literal: WgslLiteral) -> Self {
+        Self::Literal(literal)
+    }
+
+    pub fn reference(name: impl Into<String>) -> Self {
+        Self::Reference(name.into())
+    }
+
+    pub fn call(function: impl Into<String>, arguments: Vec<Self>) -> Self {
+        Self::Call {
+            function: function.into(),
+            arguments,
+        }
+    }
+
+    pub fn list(items: Vec<Self>) -> Self {
+        Self::List(items)
+    }
+
+    /// Wraps the expression in parentheses, only matters for
+    /// [`WgslExpression::to_source`] as the tree already has the grouping.
+    pub fn parenthesized(expression: Self) -> Self {
+        Self::Parenthesized(Box::new(expression))
+    }
+
+    pub fn select(condition: Self, if_true: Self, if_false: Self) -> Self {
+        Self::Select {
+            condition: Box::new(condition),
+            if_true: Box::new(if_true),
+            if_false: Box::new(if_false),
+        }
+    }
+
+    fn unary(operator: WgslUnaryOperator, right: Self) -> Self {
+        Self::Unary {
+            operator,
+            right: Box::new(right),
+        }
+    }
+
+    #[allow(clippy::should_implement_trait)] // A constructor, not an operation on `self`
+    pub fn not(right: Self) -> Self {
+        Self::unary(WgslUnaryOperator::Not, right)
+    }
+
+    pub fn negate(right: Self) -> Self {
+        Self::unary(WgslUnaryOperator::Negate, right)
+    }
+
+    pub fn bitwise_not(right: Self) -> Self {
+        Self::unary(WgslUnaryOperator::BitwiseNot, right)
+    }
+
+    pub fn operator(left: Self, operator: WgslOperator, right: Self) -> Self {
+        Self::Operator {
+            left: Box::new(left),
+            operator,
+            right: Box::new(right),
+        }
+    }
+
+    #[allow(clippy::should_implement_trait)] // A constructor, not an operation on `self`
+    pub fn add(left: Self, right: Self) -> Self {
+        Self::operator(left, WgslOperator::Add, right)
+    }
+
+    pub fn subtract(left: Self, right: Self) -> Self {
+        Self::operator(left, WgslOperator::Subtract, right)
+    }
+
+    pub fn multiply(left: Self, right: Self) -> Self {
+        Self::operator(left, WgslOperator::Multiply, right)
+    }
+
+    pub fn divide(left: Self, right: Self) -> Self {
+        Self::operator(left, WgslOperator::Divide, right)
+    }
+
+    pub fn bitwise_and(left: Self, right: Self) -> Self {
+        Self::operator(left, WgslOperator::BitwiseAnd, right)
+    }
+
+    pub fn bitwise_or(left: Self, right: Self) -> Self {
+        Self::operator(left, WgslOperator::BitwiseOr, right)
+    }
+
+    pub fn shift_left(left: Self, right: Self) -> Self {
+        Self::operator(left, WgslOperator::ShiftLeft, right)
+    }
+
+    pub fn shift_right(left: Self, right: Self) -> Self {
+        Self::operator(left, WgslOperator::ShiftRight, right)
+    }
+
+    pub fn comparison(left: Self, comparison: WgslComparison, right: Self) -> Self {
+        Self::Comparison {
+            left: Box::new(left),
+            comparison,
+            right: Box::new(right),
+        }
+    }
+
+    pub fn eq(left: Self, right: Self) -> Self {
+        Self::comparison(left, WgslComparison::Equal, right)
+    }
+
+    pub fn ne(left: Self, right: Self) -> Self {
+        Self::comparison(left, WgslComparison::NotEqual, right)
+    }
+
+    pub fn lt(left: Self, right: Self) -> Self {
+        Self::comparison(left, WgslComparison::LessThan, right)
+    }
+
+    pub fn le(left: Self, right: Self) -> Self {
+        Self::comparison(left, WgslComparison::LessThanOrEqual, right)
+    }
+
+    pub fn gt(left: Self, right: Self) -> Self {
+        Self::comparison(left, WgslComparison::GreaterThan, right)
+    }
+
+    pub fn ge(left: Self, right: Self) -> Self {
+        Self::comparison(left, WgslComparison::GreaterThanOrEqual, right)
+    }
+
+    pub fn and(left: Self, right: Self) -> Self {
+        Self::comparison(left, WgslComparison::And, right)
+    }
+
+    pub fn or(left: Self, right: Self) -> Self {
+        Self::comparison(left, WgslComparison::Or, right)
     }
 
     pub fn evaluate(&self, state: &WgslWorkspaceState) -> Result<WgslLiteral, WgslError> {
+        self.evaluate_with_warnings(state, &mut Vec::new())
+    }
+
+    /// Same as [`WgslExpression::evaluate`] but also collects warnings about
+    /// suspicious but valid expressions.
+    pub fn evaluate_with_warnings(
+        &self,
+        state: &WgslWorkspaceState,
+        warnings: &mut Vec<WgslWarning>,
+    ) -> Result<WgslLiteral, WgslError> {
         match self {
-            WgslExpression::Literal(l) => Ok(*l),
+            WgslExpression::Literal(l) => Ok(l.clone()),
             WgslExpression::Reference(r) => state.get(r).ok_or(WgslError::UndefinedVariable),
             WgslExpression::Operator {
                 left,
                 operator,
                 right,
             } => {
-                let left = left.evaluate(state)?;
-                let right = right.evaluate(state)?;
+                let left = left.evaluate_with_warnings(state, warnings)?;
+                let right = right.evaluate_with_warnings(state, warnings)?;
 
                 match operator {
                     WgslOperator::Add => match (left, right) {
                         (WgslLiteral::Integer(left), WgslLiteral::Integer(right)) => {
-                            Ok(WgslLiteral::Integer(left + right))
+                            state.int_overflow().apply(
+                                left,
+                                right,
+                                i64::checked_add,
+                                i64::wrapping_add,
+                                i64::saturating_add,
+                            )
                         }
                         (WgslLiteral::Float(left), WgslLiteral::Float(right)) => {
                             Ok(WgslLiteral::Float(left + right))
@@ -109,7 +645,13 @@ impl WgslExpression {
                     },
                     WgslOperator::Subtract => match (left, right) {
                         (WgslLiteral::Integer(left), WgslLiteral::Integer(right)) => {
-                            Ok(WgslLiteral::Integer(left - right))
+                            state.int_overflow().apply(
+                                left,
+                                right,
+                                i64::checked_sub,
+                                i64::wrapping_sub,
+                                i64::saturating_sub,
+                            )
                         }
                         (WgslLiteral::Float(left), WgslLiteral::Float(right)) => {
                             Ok(WgslLiteral::Float(left - right))
@@ -118,7 +660,13 @@ impl WgslExpression {
                     },
                     WgslOperator::Multiply => match (left, right) {
                         (WgslLiteral::Integer(left), WgslLiteral::Integer(right)) => {
-                            Ok(WgslLiteral::Integer(left * right))
+                            state.int_overflow().apply(
+                                left,
+                                right,
+                                i64::checked_mul,
+                                i64::wrapping_mul,
+                                i64::saturating_mul,
+                            )
                         }
                         (WgslLiteral::Float(left), WgslLiteral::Float(right)) => {
                             Ok(WgslLiteral::Float(left * right))
@@ -126,8 +674,21 @@ impl WgslExpression {
                         _ => Err(WgslError::InvalidExpression),
                     },
                     WgslOperator::Divide => match (left, right) {
+                        (WgslLiteral::Integer(_), WgslLiteral::Integer(0)) => {
+                            Err(WgslError::DivisionByZero)
+                        }
                         (WgslLiteral::Integer(left), WgslLiteral::Integer(right)) => {
-                            Ok(WgslLiteral::Integer(left / right))
+                            if left.checked_rem(right).is_some_and(|rem| rem != 0) {
+                                warnings.push(WgslWarning::LossyDivision { left, right });
+                            }
+
+                            state.int_overflow().apply(
+                                left,
+                                right,
+                                i64::checked_div,
+                                i64::wrapping_div,
+                                i64::saturating_div,
+                            )
                         }
                         (WgslLiteral::Float(left), WgslLiteral::Float(right)) => {
                             Ok(WgslLiteral::Float(left / right))
@@ -152,14 +713,38 @@ impl WgslExpression {
                         }
                         _ => Err(WgslError::InvalidExpression),
                     },
+                    WgslOperator::ShiftLeft | WgslOperator::ShiftRight => match (left, right) {
+                        (WgslLiteral::Integer(left), WgslLiteral::Integer(amount)) => {
+                            // Shifting by 64 or more bits or by a negative
+                            // amount is an error instead of a panic
+                            let shifted = u32::try_from(amount).ok().and_then(|amount| {
+                                if *operator == WgslOperator::ShiftLeft {
+                                    left.checked_shl(amount)
+                                } else {
+                                    left.checked_shr(amount)
+                                }
+                            });
+
+                            shifted
+                                .map(WgslLiteral::Integer)
+                                .ok_or(WgslError::InvalidShift { amount })
+                        }
+                        _ => Err(WgslError::InvalidExpression),
+                    },
                 }
             }
             WgslExpression::Unary { operator, right } => {
-                let right = right.evaluate(state)?;
+                let right = right.evaluate_with_warnings(state, warnings)?;
 
                 match (operator, right) {
                     (WgslUnaryOperator::Negate, WgslLiteral::Integer(i)) => {
-                        Ok(WgslLiteral::Integer(-i))
+                        state.int_overflow().apply(
+                            0,
+                            i,
+                            i64::checked_sub,
+                            i64::wrapping_sub,
+                            i64::saturating_sub,
+                        )
                     }
                     (WgslUnaryOperator::Negate, WgslLiteral::Float(f)) => {
                         Ok(WgslLiteral::Float(-f))
@@ -176,108 +761,637 @@ impl WgslExpression {
                 comparison,
                 right,
             } => {
-                let left = left.evaluate(state)?;
-
-                match comparison {
-                    WgslComparison::Equal => Ok(WgslLiteral::Bool(left == right.evaluate(state)?)),
-                    WgslComparison::NotEqual => {
-                        Ok(WgslLiteral::Bool(left != right.evaluate(state)?))
-                    }
-                    WgslComparison::LessThan => {
-                        Ok(WgslLiteral::Bool(left < right.evaluate(state)?))
-                    }
-                    WgslComparison::LessThanOrEqual => {
-                        Ok(WgslLiteral::Bool(left <= right.evaluate(state)?))
-                    }
-                    WgslComparison::GreaterThan => {
-                        Ok(WgslLiteral::Bool(left > right.evaluate(state)?))
-                    }
-                    WgslComparison::GreaterThanOrEqual => {
-                        Ok(WgslLiteral::Bool(left >= right.evaluate(state)?))
-                    }
-                    WgslComparison::And => match left {
-                        WgslLiteral::Bool(true) => right.evaluate(state),
-                        f @ WgslLiteral::Bool(false) => Ok(f),
-                        _ => Err(WgslError::InvalidExpression),
-                    },
-                    WgslComparison::Or => match left {
-                        WgslLiteral::Bool(false) => right.evaluate(state),
-                        f @ WgslLiteral::Bool(true) => Ok(f),
-                        _ => Err(WgslError::InvalidExpression),
-                    },
-                }
+                let left = left.evaluate_with_warnings(state, warnings)?;
+                // Only evaluated when needed, `&&` and `||` short-circuit. A
+                // parenthesized right side is a single node, so
+                // `false && (UNDEFINED + 1 > 0)` is `false` without reading
+                // `UNDEFINED`
+                let mut right = || right.evaluate_with_warnings(state, warnings);
+
+                match comparison {
+                    WgslComparison::Equal => Ok(WgslLiteral::Bool(left == right()?)),
+                    WgslComparison::NotEqual => Ok(WgslLiteral::Bool(left != right()?)),
+                    WgslComparison::LessThan => Ok(WgslLiteral::Bool(matches!(
+                        ordered(&left, &right()?)?,
+                        Some(Ordering::Less)
+                    ))),
+                    WgslComparison::LessThanOrEqual => Ok(WgslLiteral::Bool(matches!(
+                        ordered(&left, &right()?)?,
+                        Some(Ordering::Less | Ordering::Equal)
+                    ))),
+                    WgslComparison::GreaterThan => Ok(WgslLiteral::Bool(matches!(
+                        ordered(&left, &right()?)?,
+                        Some(Ordering::Greater)
+                    ))),
+                    WgslComparison::GreaterThanOrEqual => Ok(WgslLiteral::Bool(matches!(
+                        ordered(&left, &right()?)?,
+                        Some(Ordering::Greater | Ordering::Equal)
+                    ))),
+                    WgslComparison::And => match left {
+                        WgslLiteral::Bool(true) => right(),
+                        f @ WgslLiteral::Bool(false) => Ok(f),
+                        _ => Err(WgslError::InvalidExpression),
+                    },
+                    WgslComparison::Or => match left {
+                        WgslLiteral::Bool(false) => right(),
+                        f @ WgslLiteral::Bool(true) => Ok(f),
+                        _ => Err(WgslError::InvalidExpression),
+                    },
+                }
+            }
+            WgslExpression::Parenthesized(e) => e.evaluate_with_warnings(state, warnings),
+            WgslExpression::Select {
+                condition,
+                if_true,
+                if_false,
+            } => {
+                let condition = condition.evaluate_with_warnings(state, warnings)?;
+
+                if condition.is_truthy()? {
+                    if_true.evaluate_with_warnings(state, warnings)
+                } else {
+                    if_false.evaluate_with_warnings(state, warnings)
+                }
+            }
+            WgslExpression::List(items) => Ok(WgslLiteral::List(
+                items
+                    .iter()
+                    .map(|item| item.evaluate_with_warnings(state, warnings))
+                    .collect::<Result<_, _>>()?,
+            )),
+            WgslExpression::Call {
+                function,
+                arguments,
+            } => {
+                let arguments = arguments
+                    .iter()
+                    .map(|argument| argument.evaluate_with_warnings(state, warnings))
+                    .collect::<Result<Vec<_>, _>>()?;
+
+                match state.function(function) {
+                    Some(function) => function(&arguments),
+                    // These need the workspace state, so they can't be a
+                    // `WgslFunction`
+                    None if function == "abs" => builtin_abs(&arguments, state.int_overflow()),
+                    None if function == "feature" => {
+                        expect_arguments(&arguments, 1)?;
+
+                        match &arguments[0] {
+                            WgslLiteral::Str(feature) => {
+                                Ok(WgslLiteral::Bool(state.has_feature(feature)))
+                            }
+                            _ => Err(WgslError::InvalidExpression),
+                        }
+                    }
+                    None => Err(WgslError::UnknownFunction(function.clone())),
+                }
+            }
+        }
+    }
+
+    /// Returns `true` if `variable` is referenced anywhere in the expression.
+    pub fn depends_on(&self, variable: &str) -> bool {
+        match self {
+            WgslExpression::Literal(_) => false,
+            WgslExpression::Reference(r) => r == variable,
+            WgslExpression::Operator { left, right, .. }
+            | WgslExpression::Comparison { left, right, .. } => {
+                left.depends_on(variable) || right.depends_on(variable)
+            }
+            WgslExpression::Unary { right, .. } => right.depends_on(variable),
+            WgslExpression::Parenthesized(e) => e.depends_on(variable),
+            WgslExpression::Select {
+                condition,
+                if_true,
+                if_false,
+            } => {
+                condition.depends_on(variable)
+                    || if_true.depends_on(variable)
+                    || if_false.depends_on(variable)
+            }
+            WgslExpression::List(items)
+            | WgslExpression::Call {
+                arguments: items, ..
+            } => items.iter().any(|item| item.depends_on(variable)),
+        }
+    }
+
+    /// Calls `visitor` with the name of every variable referenced in the
+    /// expression.
+    pub fn visit_references(&self, visitor: &mut dyn FnMut(&str)) {
+        self.visit_typed_references(WgslInferredType::Unknown, &mut |name, _| visitor(name));
+    }
+
+    /// Same as [`WgslExpression::visit_references`] but also guesses the
+    /// type of every variable from where it is used, `expected` is the type
+    /// this whole expression is used as.
+    pub fn visit_typed_references(
+        &self,
+        expected: WgslInferredType,
+        visitor: &mut dyn FnMut(&str, WgslInferredType),
+    ) {
+        // `X == 1` and `X & true` have the type of the other side
+        let both_like = |left: &Self, right: &Self, fallback| {
+            let (left_type, right_type) = (right.literal_type(), left.literal_type());
+            (
+                left_type.unwrap_or(fallback),
+                right_type.unwrap_or(fallback),
+            )
+        };
+
+        let (left, right, left_type, right_type) = match self {
+            WgslExpression::Literal(_) => return,
+            WgslExpression::Reference(r) => return visitor(r, expected),
+            WgslExpression::Parenthesized(e) => return e.visit_typed_references(expected, visitor),
+            WgslExpression::Select {
+                condition,
+                if_true,
+                if_false,
+            } => {
+                condition.visit_typed_references(WgslInferredType::Bool, visitor);
+                if_true.visit_typed_references(expected, visitor);
+
+                return if_false.visit_typed_references(expected, visitor);
+            }
+            WgslExpression::Unary { operator, right } => {
+                let expected = match operator {
+                    WgslUnaryOperator::Not => WgslInferredType::Bool,
+                    WgslUnaryOperator::Negate => WgslInferredType::Number,
+                    WgslUnaryOperator::BitwiseNot => WgslInferredType::Integer,
+                };
+
+                return right.visit_typed_references(expected, visitor);
+            }
+            WgslExpression::List(items) => {
+                for item in items.iter() {
+                    item.visit_typed_references(WgslInferredType::Unknown, visitor);
+                }
+
+                return;
+            }
+            WgslExpression::Call {
+                function,
+                arguments,
+            } => {
+                let expected = match function.as_str() {
+                    "min" | "max" | "clamp" | "abs" | "between" => WgslInferredType::Number,
+                    "len" => WgslInferredType::List,
+                    _ => WgslInferredType::Unknown,
+                };
+
+                for argument in arguments.iter() {
+                    argument.visit_typed_references(expected, visitor);
+                }
+
+                return;
+            }
+            WgslExpression::Operator {
+                left,
+                operator,
+                right,
+            } => {
+                let (left_type, right_type) = match operator {
+                    WgslOperator::BitwiseAnd | WgslOperator::BitwiseOr => {
+                        both_like(left, right, WgslInferredType::Unknown)
+                    }
+                    WgslOperator::ShiftLeft | WgslOperator::ShiftRight => {
+                        (WgslInferredType::Integer, WgslInferredType::Integer)
+                    }
+                    _ => (WgslInferredType::Number, WgslInferredType::Number),
+                };
+
+                (left, right, left_type, right_type)
+            }
+            WgslExpression::Comparison {
+                left,
+                comparison,
+                right,
+            } => {
+                let (left_type, right_type) = match comparison {
+                    WgslComparison::Equal | WgslComparison::NotEqual => {
+                        both_like(left, right, WgslInferredType::Unknown)
+                    }
+                    WgslComparison::And | WgslComparison::Or => {
+                        (WgslInferredType::Bool, WgslInferredType::Bool)
+                    }
+                    _ => (WgslInferredType::Number, WgslInferredType::Number),
+                };
+
+                (left, right, left_type, right_type)
+            }
+        };
+
+        left.visit_typed_references(left_type, visitor);
+        right.visit_typed_references(right_type, visitor);
+    }
+
+    /// The type of a literal like `1`, `-1.0` or `"release"`.
+    fn literal_type(&self) -> Option<WgslInferredType> {
+        match self {
+            WgslExpression::Literal(literal) => Some(WgslInferredType::of_literal(literal)),
+            WgslExpression::Unary { right, .. } | WgslExpression::Parenthesized(right) => {
+                right.literal_type()
+            }
+            _ => None,
+        }
+    }
+
+    /// The value of an expression that doesn't use any variable or function,
+    /// like `2 + 2 == 4`. `None` if it does, or if evaluating it fails so the
+    /// error is still reported when the shader is written.
+    pub(crate) fn constant_fold(&self) -> Option<WgslLiteral> {
+        if self.is_constant() {
+            self.evaluate(&WgslWorkspaceState::default()).ok()
+        } else {
+            None
+        }
+    }
+
+    fn is_constant(&self) -> bool {
+        match self {
+            WgslExpression::Literal(_) => true,
+            // Functions can be replaced with `register_function`
+            WgslExpression::Reference(_) | WgslExpression::Call { .. } => false,
+            WgslExpression::Operator { left, right, .. }
+            | WgslExpression::Comparison { left, right, .. } => {
+                left.is_constant() && right.is_constant()
+            }
+            WgslExpression::Unary { right, .. } | WgslExpression::Parenthesized(right) => {
+                right.is_constant()
+            }
+            WgslExpression::List(items) => items.iter().all(WgslExpression::is_constant),
+            WgslExpression::Select {
+                condition,
+                if_true,
+                if_false,
+            } => condition.is_constant() && if_true.is_constant() && if_false.is_constant(),
+        }
+    }
+
+    /// Checks for type errors that happen no matter what the variables are,
+    /// like `true + 1` or `"x" < 2`, without evaluating the expression.
+    /// This is best-effort: variables can have any type, so `X + 1` passes
+    /// even if `X` turns out to be a bool.
+    pub fn typecheck(&self) -> Result<(), WgslError> {
+        self.static_type().map(|_| ())
+    }
+
+    /// The type this expression always evaluates to, `None` if it depends on
+    /// variables or functions.
+    fn static_type(&self) -> Result<Option<StaticType>, WgslError> {
+        use StaticType::*;
+
+        // Both sides have to be one of `allowed`, or unknown, and if both
+        // are known they have to be the same
+        let same_of = |left: &Self, right: &Self, allowed: &[StaticType]| match (
+            left.static_type()?,
+            right.static_type()?,
+        ) {
+            (Some(ty), _) | (_, Some(ty)) if !allowed.contains(&ty) => {
+                Err(WgslError::InvalidExpression)
+            }
+            (Some(left), Some(right)) if left != right => Err(WgslError::InvalidExpression),
+            (left, right) => Ok(left.or(right)),
+        };
+
+        let only = |expression: &Self, allowed: &[StaticType]| match expression.static_type()? {
+            Some(ty) if !allowed.contains(&ty) => Err(WgslError::InvalidExpression),
+            ty => Ok(ty),
+        };
+
+        Ok(match self {
+            WgslExpression::Literal(literal) => Some(match literal {
+                WgslLiteral::Integer(_) => Integer,
+                WgslLiteral::Float(_) => Float,
+                WgslLiteral::Bool(_) => Bool,
+                WgslLiteral::List(_) => List,
+                WgslLiteral::Str(_) => Str,
+            }),
+            WgslExpression::Reference(_) => None,
+            WgslExpression::Parenthesized(e) => e.static_type()?,
+            // Either side can be selected, so only a type both share is known
+            WgslExpression::Select {
+                condition,
+                if_true,
+                if_false,
+            } => {
+                condition.static_type()?;
+
+                match (if_true.static_type()?, if_false.static_type()?) {
+                    (Some(left), Some(right)) if left == right => Some(left),
+                    _ => None,
+                }
+            }
+            WgslExpression::List(items) => {
+                for item in items.iter() {
+                    item.static_type()?;
+                }
+
+                Some(List)
+            }
+            WgslExpression::Call {
+                function,
+                arguments,
+            } => {
+                for argument in arguments.iter() {
+                    argument.static_type()?;
+                }
+
+                // Only the built-in functions are known, others can be
+                // registered with any name
+                match function.as_str() {
+                    "len" => Some(Integer),
+                    "between" | "feature" => Some(Bool),
+                    _ => None,
+                }
+            }
+            WgslExpression::Unary { operator, right } => match operator {
+                WgslUnaryOperator::Not => only(right, &[Bool])?.and(Some(Bool)),
+                WgslUnaryOperator::Negate => only(right, &[Integer, Float])?,
+                WgslUnaryOperator::BitwiseNot => only(right, &[Integer])?.and(Some(Integer)),
+            },
+            WgslExpression::Operator {
+                left,
+                operator,
+                right,
+            } => match operator {
+                WgslOperator::Add
+                | WgslOperator::Subtract
+                | WgslOperator::Multiply
+                | WgslOperator::Divide => same_of(left, right, &[Integer, Float])?,
+                WgslOperator::BitwiseAnd | WgslOperator::BitwiseOr => {
+                    same_of(left, right, &[Integer, Bool])?
+                }
+                WgslOperator::ShiftLeft | WgslOperator::ShiftRight => {
+                    same_of(left, right, &[Integer])?;
+                    Some(Integer)
+                }
+            },
+            WgslExpression::Comparison {
+                left,
+                comparison,
+                right,
+            } => {
+                match comparison {
+                    WgslComparison::Equal | WgslComparison::NotEqual => {
+                        left.static_type()?;
+                        right.static_type()?;
+                    }
+                    WgslComparison::And | WgslComparison::Or => {
+                        same_of(left, right, &[Bool])?;
+                    }
+                    // Only numbers can be ordered, integers and floats can
+                    // be mixed
+                    _ => {
+                        only(left, &[Integer, Float])?;
+                        only(right, &[Integer, Float])?;
+                    }
+                }
+
+                Some(Bool)
+            }
+        })
+    }
+
+    /// Converts the expression back into source code with normalized
+    /// spacing, e.g. `x==1` becomes `x == 1`.
+    pub fn to_source(&self) -> String {
+        let mut output = String::new();
+        self.write_source(&mut output);
+        output
+    }
+
+    fn write_source(&self, output: &mut String) {
+        match self {
+            WgslExpression::Literal(l) => write_literal_source(l, output),
+            WgslExpression::Reference(r) => output.push_str(r),
+            WgslExpression::Operator {
+                left,
+                operator,
+                right,
+            } => {
+                left.write_source(output);
+                output.push(' ');
+                output.push_str(operator.symbol());
+                output.push(' ');
+                right.write_source(output);
+            }
+            WgslExpression::Unary { operator, right } => {
+                output.push_str(operator.symbol());
+                right.write_source(output);
+            }
+            WgslExpression::Comparison {
+                left,
+                comparison,
+                right,
+            } => {
+                left.write_source(output);
+                output.push(' ');
+                output.push_str(comparison.symbol());
+                output.push(' ');
+                right.write_source(output);
+            }
+            WgslExpression::Parenthesized(e) => {
+                output.push('(');
+                e.write_source(output);
+                output.push(')');
+            }
+            WgslExpression::Select {
+                condition,
+                if_true,
+                if_false,
+            } => {
+                condition.write_source(output);
+                output.push_str(" ? ");
+                if_true.write_source(output);
+                output.push_str(" : ");
+                if_false.write_source(output);
+            }
+            WgslExpression::List(items) => {
+                output.push('[');
+                write_list_source(items, output);
+                output.push(']');
+            }
+            WgslExpression::Call {
+                function,
+                arguments,
+            } => {
+                output.push_str(function);
+                output.push('(');
+                write_list_source(arguments, output);
+                output.push(')');
             }
-            WgslExpression::Parenthesized(e) => e.evaluate(state),
         }
     }
 
+    /// Applies operator precedence to the right-leaning tree built by the
+    /// parser, operators of the same priority are evaluated left to right.
+    /// Parentheses are never crossed, `(a + b) * c` keeps its grouping.
     fn reorder(&mut self) {
-        // TODO
-
-        // match self {
-        //     WgslExpression::Operator {
-        //         left,
-        //         operator,
-        //         right,
-        //     } => {
-        //         left.reorder();
-        //         right.reorder();
-
-        //         let self_priority = operator.priority();
-
-        //         let left_priority = if let Self::Operator { ref operator, .. } = left.as_ref() {
-        //             operator.priority()
-        //         } else {
-        //             0
-        //         };
-
-        //         let right_priority = if let Self::Operator { ref operator, .. } = right.as_ref() {
-        //             operator.priority()
-        //         } else {
-        //             0
-        //         };
-
-        //         if left_priority < self_priority && left_priority < right_priority {
-        //         } else if right_priority < self_priority && right_priority < left_priority {
-        //         }
-        //     }
-        //     WgslExpression::Unary { operator, right } => todo!(),
-        //     WgslExpression::Comparison {
-        //         left,
-        //         comparison,
-        //         right,
-        //     } => todo!(),
-        //     WgslExpression::Parenthesized(_) => todo!(),
-        //     _ => (),
-        // }
-    }
-
-    fn from_chars<I: Iterator<Item = char> + Clone>(
-        chars: &mut I,
-        shallow: bool,
-    ) -> Result<Option<Self>, WgslError> {
-        let single = match chars.clone().next() {
-            Some('!') => {
-                chars.next().unwrap();
+        match self {
+            Self::Operator { .. } | Self::Comparison { .. } => {
+                let mut operands = Vec::new();
+                let mut operators = Vec::new();
+
+                // Flatten `a + (b * (c - d))` into `[a, b, c, d]` and `[+, *, -]`
+                let mut current = std::mem::replace(self, Self::Literal(WgslLiteral::Bool(false)));
+
+                loop {
+                    let (left, operator, right) = match current {
+                        Self::Operator {
+                            left,
+                            operator,
+                            right,
+                        } => (left, WgslBinaryOperator::Operator(operator), right),
+                        Self::Comparison {
+                            left,
+                            comparison,
+                            right,
+                        } => (left, WgslBinaryOperator::Comparison(comparison), right),
+                        other => {
+                            operands.push(other);
+                            break;
+                        }
+                    };
 
-                Self::Unary {
-                    operator: WgslUnaryOperator::Not,
-                    right: Box::new(Self::from_chars(chars, true)?.ok_or(WgslError::NoExpression)?),
+                    operands.push(*left);
+                    operators.push(operator);
+                    current = *right;
+                }
+
+                for operand in operands.iter_mut() {
+                    operand.reorder();
+                }
+
+                let mut operands = operands.into_iter();
+                let mut output = vec![operands.next().unwrap()];
+                let mut pending: Vec<WgslBinaryOperator> = Vec::new();
+
+                let join = |output: &mut Vec<Self>, operator: WgslBinaryOperator| {
+                    let right = output.pop().unwrap();
+                    let left = output.pop().unwrap();
+                    output.push(operator.join(left, right));
+                };
+
+                for (operator, operand) in operators.into_iter().zip(operands) {
+                    while let Some(&previous) = pending.last() {
+                        if previous.priority() < operator.priority() {
+                            break;
+                        }
+
+                        pending.pop();
+                        join(&mut output, previous);
+                    }
+
+                    pending.push(operator);
+                    output.push(operand);
+                }
+
+                while let Some(operator) = pending.pop() {
+                    join(&mut output, operator);
+                }
+
+                *self = output.pop().unwrap();
+            }
+            Self::Unary { right, .. } => right.reorder(),
+            Self::Parenthesized(e) => e.reorder(),
+            Self::Select {
+                condition,
+                if_true,
+                if_false,
+            } => {
+                condition.reorder();
+                if_true.reorder();
+                if_false.reorder();
+            }
+            Self::List(items)
+            | Self::Call {
+                arguments: items, ..
+            } => {
+                for item in items.iter_mut() {
+                    item.reorder();
                 }
             }
-            Some('~') => {
+            Self::Literal(_) | Self::Reference(_) => (),
+        }
+    }
+
+    /// Parses comma separated expressions up to and including `close`, a
+    /// trailing comma is allowed.
+    fn list_from_chars(chars: &mut Chars, close: char) -> Result<Vec<Self>, WgslError> {
+        let mut items = Vec::new();
+
+        loop {
+            skip_whitespace(chars);
+
+            if chars.clone().next() == Some(close) {
                 chars.next().unwrap();
+                return Ok(items);
+            }
 
-                Self::Unary {
-                    operator: WgslUnaryOperator::BitwiseNot,
-                    right: Box::new(Self::from_chars(chars, true)?.ok_or(WgslError::NoExpression)?),
+            items.push(Self::select_from_chars(chars)?.ok_or(WgslError::NoExpression)?);
+
+            match chars.clone().next() {
+                Some(',') => _ = chars.next(),
+                Some(ch) if ch == close => {
+                    chars.next().unwrap();
+                    return Ok(items);
                 }
+                _ if close == ')' => Err(WgslError::NoClosingParenthesis)?,
+                _ => Err(WgslError::NoClosingBracket)?,
             }
-            Some('-') => {
+        }
+    }
+
+    /// Parses `condition ? if_true : if_false`, which binds weaker than any
+    /// operator so `a || b ? 1 : 2` selects on `a || b`. Nested selects in
+    /// `if_false` chain like `a ? 1 : b ? 2 : 3`.
+    fn select_from_chars(chars: &mut Chars) -> Result<Option<Self>, WgslError> {
+        let Some(condition) = Self::from_chars(chars, false)? else {
+            return Ok(None);
+        };
+
+        skip_whitespace(chars);
+
+        if chars.clone().next() != Some('?') {
+            return Ok(Some(condition));
+        }
+
+        chars.next().unwrap();
+
+        let if_true = Self::select_from_chars(chars)?.ok_or(WgslError::NoExpression)?;
+
+        skip_whitespace(chars);
+
+        if chars.clone().next() != Some(':') {
+            Err(WgslError::NoColon)?;
+        }
+
+        chars.next().unwrap();
+
+        let if_false = Self::select_from_chars(chars)?.ok_or(WgslError::NoExpression)?;
+
+        Ok(Some(Self::select(condition, if_true, if_false)))
+    }
+
+    fn from_chars(chars: &mut Chars, shallow: bool) -> Result<Option<Self>, WgslError> {
+        skip_whitespace(chars);
+
+        let single = match chars.clone().next() {
+            Some(ch @ ('!' | '~' | '-')) => {
                 chars.next().unwrap();
 
+                let operator = match ch {
+                    '!' => WgslUnaryOperator::Not,
+                    '~' => WgslUnaryOperator::BitwiseNot,
+                    _ => WgslUnaryOperator::Negate,
+                };
+
+                // Unary operators stack, `--5` is `5` and `!!true` is `true`.
+                // Without an operand (`-`, `!)`, `~ * 2`) `chars` is left where
+                // the operand should start, so the error points at it.
                 Self::Unary {
-                    operator: WgslUnaryOperator::Negate,
+                    operator,
                     right: Box::new(Self::from_chars(chars, true)?.ok_or(WgslError::NoExpression)?),
                 }
             }
@@ -285,14 +1399,34 @@ impl WgslExpression {
                 chars.next().unwrap();
 
                 let expr =
-                    Box::new(Self::from_chars(chars, false)?.ok_or(WgslError::NoExpression)?);
+                    Box::new(Self::select_from_chars(chars)?.ok_or(WgslError::NoExpression)?);
 
-                if !chars.next().is_some_and(|c| c == ')') {
+                if chars.next() != Some(')') {
                     Err(WgslError::NoClosingParenthesis)?;
                 }
 
                 Self::Parenthesized(expr)
             }
+            Some('[') => {
+                chars.next().unwrap();
+
+                Self::List(Self::list_from_chars(chars, ']')?)
+            }
+            Some('"') => {
+                chars.next().unwrap();
+
+                let mut buffer = String::new();
+
+                loop {
+                    match chars.next().ok_or(WgslError::UnclosedString)? {
+                        '"' => break,
+                        '\\' => buffer.push(chars.next().ok_or(WgslError::UnclosedString)?),
+                        ch => buffer.push(ch),
+                    }
+                }
+
+                Self::Literal(WgslLiteral::Str(buffer))
+            }
             Some(ch) if ch.is_numeric() => {
                 let mut period = false; // The dot in floats
                 let mut buffer = String::new();
@@ -356,28 +1490,58 @@ impl WgslExpression {
                             .map_err(WgslError::ParseFloatError)?,
                     ))
                 } else {
-                    Self::Literal(WgslLiteral::Integer(
-                        i64::from_str_radix(&buffer[buffer_slice_start..], radix)
-                            .map_err(WgslError::ParseIntError)?,
-                    ))
+                    let digits = &buffer[buffer_slice_start..];
+
+                    // `0x`, `0o` and `0b` literals are bit patterns, so masks
+                    // like `0x8000000000000000` equal `BIT_63`
+                    Self::Literal(WgslLiteral::Integer(if radix == 10 {
+                        digits.parse().map_err(WgslError::ParseIntError)?
+                    } else {
+                        u64::from_str_radix(digits, radix).map_err(WgslError::ParseIntError)? as i64
+                    }))
                 }
             }
             Some(ch) if ch.is_alphabetic() || ch == '_' => {
                 let mut buffer = String::new();
                 buffer.push(chars.next().unwrap());
 
+                // `graphics.quality.level` is a single name, a `.` is only
+                // part of it when a letter follows so `A..B` stays a range
                 while let Some(ch) = chars.clone().next() {
-                    if ch.is_alphanumeric() || ch == '_' {
+                    let dotted = ch == '.'
+                        && chars
+                            .clone()
+                            .nth(1)
+                            .is_some_and(|next| next.is_alphabetic() || next == '_');
+
+                    if ch.is_alphanumeric() || ch == '_' || dotted {
                         buffer.push(chars.next().unwrap());
                     } else {
                         break;
                     }
                 }
 
+                // Identifiers are restricted to `[A-Za-z_][A-Za-z0-9_]*` like in
+                // WGSL, joined by `.` for config fields. Other letters are consumed
+                // only to report them, very long names are rejected too.
+                if !buffer.is_ascii() || buffer.len() > MAX_IDENTIFIER_LENGTH {
+                    return Err(WgslError::InvalidIdentifier(buffer));
+                }
+
+                // The whole identifier is read first, so only exactly `true`
+                // and `false` are bools. `True`, `TRUE` and `true_flag` are
+                // variables, and a variable named `true` can't be read.
                 if buffer == "true" {
                     Self::Literal(WgslLiteral::Bool(true))
                 } else if buffer == "false" {
                     Self::Literal(WgslLiteral::Bool(false))
+                } else if matches!(chars.clone().next(), Some('(')) {
+                    chars.next().unwrap();
+
+                    Self::Call {
+                        function: buffer,
+                        arguments: Self::list_from_chars(chars, ')')?,
+                    }
                 } else {
                     Self::Reference(buffer)
                 }
@@ -389,6 +1553,8 @@ impl WgslExpression {
             return Ok(Some(single));
         }
 
+        skip_whitespace(chars);
+
         match chars.clone().next() {
             Some('+') => {
                 chars.next().unwrap();
@@ -499,6 +1665,20 @@ impl WgslExpression {
             Some('>') => {
                 chars.next().unwrap();
 
+                if matches!(chars.clone().next(), Some('>')) {
+                    chars.next().unwrap();
+
+                    let left = Box::new(single);
+                    let right =
+                        Box::new(Self::from_chars(chars, false)?.ok_or(WgslError::NoExpression)?);
+
+                    return Ok(Some(WgslExpression::Operator {
+                        left,
+                        operator: WgslOperator::ShiftRight,
+                        right,
+                    }));
+                }
+
                 let mut comparison = WgslComparison::GreaterThan;
 
                 if matches!(chars.clone().next(), Some('=')) {
@@ -519,6 +1699,20 @@ impl WgslExpression {
             Some('<') => {
                 chars.next().unwrap();
 
+                if matches!(chars.clone().next(), Some('<')) {
+                    chars.next().unwrap();
+
+                    let left = Box::new(single);
+                    let right =
+                        Box::new(Self::from_chars(chars, false)?.ok_or(WgslError::NoExpression)?);
+
+                    return Ok(Some(WgslExpression::Operator {
+                        left,
+                        operator: WgslOperator::ShiftLeft,
+                        right,
+                    }));
+                }
+
                 let mut comparison = WgslComparison::LessThan;
 
                 if matches!(chars.clone().next(), Some('=')) {
@@ -576,3 +1770,562 @@ impl WgslExpression {
         }
     }
 }
+
+/// Removes a trailing `// comment`, a single `/` is a division and `//`
+/// inside a string is kept.
+fn strip_comment(source: &str) -> &str {
+    let mut in_string = false;
+    let mut escaped = false;
+    let mut previous_slash = false;
+
+    for (i, ch) in source.char_indices() {
+        match ch {
+            _ if escaped => escaped = false,
+            '\\' if in_string => escaped = true,
+            '"' => in_string = !in_string,
+            '/' if !in_string && previous_slash => return &source[..i - 1],
+            _ => (),
+        }
+
+        previous_slash = ch == '/' && !in_string;
+    }
+
+    source
+}
+
+/// Splits a trailing `/// doc comment` from a directive, any other
+/// `// comment` is removed.
+pub(crate) fn split_doc_comment(source: &str) -> (&str, Option<&str>) {
+    let code = strip_comment(source);
+
+    let doc = source[code.len()..]
+        .strip_prefix("///")
+        .map(str::trim)
+        .filter(|doc| !doc.is_empty());
+
+    (code.trim_end(), doc)
+}
+
+/// Errors if `literal` can't be used in `<`, `<=`, `>` or `>=`.
+/// Orders two numbers for `<`, `<=`, `>` and `>=`, an integer and a float are
+/// compared as floats like in `between`. `None` if either is NaN, anything
+/// other than numbers can't be ordered.
+fn ordered(left: &WgslLiteral, right: &WgslLiteral) -> Result<Option<Ordering>, WgslError> {
+    let as_float = |literal: &WgslLiteral| match literal {
+        WgslLiteral::Integer(i) => Ok(*i as f64),
+        WgslLiteral::Float(f) => Ok(*f),
+        _ => Err(WgslError::InvalidExpression),
+    };
+
+    match (left, right) {
+        (WgslLiteral::Integer(left), WgslLiteral::Integer(right)) => Ok(Some(left.cmp(right))),
+        (left, right) => Ok(as_float(left)?.partial_cmp(&as_float(right)?)),
+    }
+}
+
+fn skip_whitespace(chars: &mut Chars) {
+    *chars = chars.as_str().trim_start().chars();
+}
+
+fn write_list_source(items: &[WgslExpression], output: &mut String) {
+    for (i, item) in items.iter().enumerate() {
+        if i > 0 {
+            output.push_str(", ");
+        }
+
+        item.write_source(output);
+    }
+}
+
+/// Quotes a string, escaping `"` and `\`.
+fn string_to_source(s: &str) -> String {
+    format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+/// Makes sure a float is still a float when parsed again, `5.0` would
+/// otherwise be written as `5`.
+fn float_to_string(f: f64) -> String {
+    let mut f = f.to_string();
+
+    if f.bytes().all(|b| b.is_ascii_digit() || b == b'-') {
+        f.push_str(".0");
+    }
+
+    f
+}
+
+fn fixed_float_to_string(f: f64, precision: usize) -> String {
+    let mut f = format!("{f:.precision$}");
+
+    if !f.contains('.') {
+        f.push_str(".0");
+    }
+
+    f
+}
+
+fn write_literal_source(literal: &WgslLiteral, output: &mut String) {
+    match literal {
+        WgslLiteral::Integer(i) => output.push_str(&i.to_string()),
+        // There are no literals for these, only expressions that give them
+        WgslLiteral::Float(f) if f.is_nan() => output.push_str("(0.0 / 0.0)"),
+        WgslLiteral::Float(f) if f.is_infinite() && *f > 0.0 => output.push_str("(1.0 / 0.0)"),
+        WgslLiteral::Float(f) if f.is_infinite() => output.push_str("(-1.0 / 0.0)"),
+        WgslLiteral::Float(f) => output.push_str(&float_to_string(*f)),
+        WgslLiteral::Bool(b) => output.push_str(&b.to_string()),
+        WgslLiteral::Str(s) => output.push_str(&string_to_source(s)),
+        WgslLiteral::List(items) => {
+            output.push('[');
+
+            for (i, item) in items.iter().enumerate() {
+                if i > 0 {
+                    output.push_str(", ");
+                }
+
+                write_literal_source(item, output);
+            }
+
+            output.push(']');
+        }
+    }
+}
+
+pub(crate) const BUILTIN_FUNCTIONS: &[(&str, WgslFunction)] = &[
+    ("min", builtin_min),
+    ("max", builtin_max),
+    ("clamp", builtin_clamp),
+    ("len", builtin_len),
+    ("between", builtin_between),
+];
+
+fn expect_arguments(arguments: &[WgslLiteral], expected: usize) -> Result<(), WgslError> {
+    if arguments.len() == expected {
+        Ok(())
+    } else {
+        Err(WgslError::InvalidArgumentCount {
+            expected,
+            found: arguments.len(),
+        })
+    }
+}
+
+fn builtin_min(arguments: &[WgslLiteral]) -> Result<WgslLiteral, WgslError> {
+    expect_arguments(arguments, 2)?;
+
+    match (&arguments[0], &arguments[1]) {
+        (WgslLiteral::Integer(a), WgslLiteral::Integer(b)) => Ok(WgslLiteral::Integer(*a.min(b))),
+        (WgslLiteral::Float(a), WgslLiteral::Float(b)) => Ok(WgslLiteral::Float(a.min(*b))),
+        _ => Err(WgslError::InvalidExpression),
+    }
+}
+
+fn builtin_max(arguments: &[WgslLiteral]) -> Result<WgslLiteral, WgslError> {
+    expect_arguments(arguments, 2)?;
+
+    match (&arguments[0], &arguments[1]) {
+        (WgslLiteral::Integer(a), WgslLiteral::Integer(b)) => Ok(WgslLiteral::Integer(*a.max(b))),
+        (WgslLiteral::Float(a), WgslLiteral::Float(b)) => Ok(WgslLiteral::Float(a.max(*b))),
+        _ => Err(WgslError::InvalidExpression),
+    }
+}
+
+fn builtin_clamp(arguments: &[WgslLiteral]) -> Result<WgslLiteral, WgslError> {
+    expect_arguments(arguments, 3)?;
+
+    match (&arguments[0], &arguments[1], &arguments[2]) {
+        (WgslLiteral::Integer(x), WgslLiteral::Integer(low), WgslLiteral::Integer(high)) => {
+            Ok(WgslLiteral::Integer(*x.max(low).min(high)))
+        }
+        (WgslLiteral::Float(x), WgslLiteral::Float(low), WgslLiteral::Float(high)) => {
+            Ok(WgslLiteral::Float(x.max(*low).min(*high)))
+        }
+        _ => Err(WgslError::InvalidExpression),
+    }
+}
+
+/// `abs(i64::MIN)` overflows like `-i64::MIN`.
+fn builtin_abs(
+    arguments: &[WgslLiteral],
+    int_overflow: IntOverflow,
+) -> Result<WgslLiteral, WgslError> {
+    expect_arguments(arguments, 1)?;
+
+    match &arguments[0] {
+        WgslLiteral::Integer(x) if *x < 0 => int_overflow.apply(
+            0,
+            *x,
+            i64::checked_sub,
+            i64::wrapping_sub,
+            i64::saturating_sub,
+        ),
+        WgslLiteral::Integer(x) => Ok(WgslLiteral::Integer(*x)),
+        WgslLiteral::Float(x) => Ok(WgslLiteral::Float(x.abs())),
+        _ => Err(WgslError::InvalidExpression),
+    }
+}
+
+fn builtin_len(arguments: &[WgslLiteral]) -> Result<WgslLiteral, WgslError> {
+    expect_arguments(arguments, 1)?;
+
+    match &arguments[0] {
+        WgslLiteral::List(items) => Ok(WgslLiteral::Integer(items.len() as i64)),
+        _ => Err(WgslError::InvalidExpression),
+    }
+}
+
+/// `between(x, low, high)` is `low <= x && x < high`, so `high` is excluded.
+/// Integers are converted to floats when mixed with floats.
+fn builtin_between(arguments: &[WgslLiteral]) -> Result<WgslLiteral, WgslError> {
+    expect_arguments(arguments, 3)?;
+
+    match (&arguments[0], &arguments[1], &arguments[2]) {
+        (WgslLiteral::Integer(x), WgslLiteral::Integer(low), WgslLiteral::Integer(high)) => {
+            Ok(WgslLiteral::Bool(low <= x && x < high))
+        }
+        (x, low, high) => {
+            let as_float = |literal: &WgslLiteral| match literal {
+                WgslLiteral::Integer(i) => Ok(*i as f64),
+                WgslLiteral::Float(f) => Ok(*f),
+                _ => Err(WgslError::InvalidExpression),
+            };
+
+            let (x, low, high) = (as_float(x)?, as_float(low)?, as_float(high)?);
+
+            Ok(WgslLiteral::Bool(low <= x && x < high))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn state_with(globals: &[(&str, WgslLiteral)]) -> WgslWorkspaceState {
+        let mut state = WgslWorkspaceState::default();
+
+        for (name, value) in globals {
+            state
+                .global_variables
+                .insert(name.to_string(), value.clone());
+        }
+
+        state
+    }
+
+    #[test]
+    fn floats_always_have_a_decimal_point() {
+        assert_eq!(WgslLiteral::Float(2.0).to_wgsl_string(), "2.0");
+        assert_eq!(
+            WgslLiteral::Float(1e21).to_wgsl_string(),
+            "1000000000000000000000.0"
+        );
+        assert_eq!(
+            WgslLiteral::Float(2.4).to_wgsl_string_with_precision(Some(0)),
+            "2.0"
+        );
+        assert_eq!(
+            WgslLiteral::Float(1.0 / 3.0).to_wgsl_string_with_precision(Some(6)),
+            "0.333333"
+        );
+        assert_eq!(
+            WgslLiteral::List(vec![WgslLiteral::Float(0.5), WgslLiteral::Float(1.25)])
+                .to_wgsl_string_with_precision(Some(1)),
+            "array(0.5, 1.2)"
+        );
+    }
+
+    #[test]
+    fn rounding_to_zero_is_detected() {
+        assert!(WgslLiteral::Float(0.000001).rounds_to_zero(2));
+        assert!(WgslLiteral::Float(-0.001).rounds_to_zero(2));
+        assert!(!WgslLiteral::Float(0.005).rounds_to_zero(2));
+        assert!(!WgslLiteral::Float(0.0).rounds_to_zero(0));
+        assert!(
+            WgslLiteral::List(vec![WgslLiteral::Float(1.0), WgslLiteral::Float(0.01)])
+                .rounds_to_zero(1)
+        );
+    }
+
+    #[test]
+    fn non_finite_floats_are_written_as_expressions() {
+        let state = WgslWorkspaceState::default();
+        let huge = format!("1{}.0", "0".repeat(400));
+
+        for (expression, expected) in [
+            (WgslExpression::new(&huge).unwrap(), f64::INFINITY),
+            (
+                WgslExpression::new(&format!("-{huge}")).unwrap(),
+                f64::NEG_INFINITY,
+            ),
+            (
+                WgslExpression::Literal(WgslLiteral::Float(f64::INFINITY)),
+                f64::INFINITY,
+            ),
+        ] {
+            let source = expression.to_source();
+            let parsed = WgslExpression::new(&source).unwrap();
+
+            assert!(matches!(parsed.evaluate(&state), Ok(WgslLiteral::Float(f)) if f == expected));
+            assert_eq!(parsed.to_source(), source);
+        }
+
+        let nan = WgslExpression::Literal(WgslLiteral::Float(f64::NAN)).to_source();
+        let nan = WgslExpression::new(&nan).unwrap().evaluate(&state);
+        assert!(matches!(nan, Ok(WgslLiteral::Float(f)) if f.is_nan()));
+    }
+
+    #[test]
+    fn globals_and_functions_with_the_same_name() {
+        let mut state = state_with(&[
+            ("max", WgslLiteral::Integer(5)),
+            ("seven", WgslLiteral::Integer(1)),
+        ]);
+        state
+            .functions
+            .insert("seven".to_string(), |_| Ok(WgslLiteral::Integer(7)));
+
+        assert_eq!(state.eval_expr("max > 3").unwrap(), WgslLiteral::Bool(true));
+        assert_eq!(
+            state.eval_expr("max(1, 2)").unwrap(),
+            WgslLiteral::Integer(2)
+        );
+        assert_eq!(
+            state.eval_expr("max(max, 9)").unwrap(),
+            WgslLiteral::Integer(9)
+        );
+        assert_eq!(state.eval_expr("seven").unwrap(), WgslLiteral::Integer(1));
+        assert_eq!(state.eval_expr("seven()").unwrap(), WgslLiteral::Integer(7));
+        assert!(state.eval_expr("min").is_err());
+    }
+
+    #[test]
+    fn comments_are_stripped_but_division_is_kept() {
+        let state = state_with(&[("A", WgslLiteral::Integer(8))]);
+
+        assert_eq!(
+            state.eval_expr("A/2//half").unwrap(),
+            WgslLiteral::Integer(4)
+        );
+        assert_eq!(
+            state.eval_expr("A / 2 / 2 // a / b // c").unwrap(),
+            WgslLiteral::Integer(2)
+        );
+        assert_eq!(
+            state.eval_expr("\"a//b\" == \"a//b\" // note").unwrap(),
+            WgslLiteral::Bool(true)
+        );
+        assert!(state.eval_expr("A / / 2").is_err());
+    }
+
+    #[test]
+    fn syntax_errors_point_into_the_original_source() {
+        let Err(WgslError::InvalidSyntax {
+            offset,
+            snippet,
+            error,
+        }) = WgslExpression::new("1 + + 2")
+        else {
+            panic!("`1 + + 2` should not parse");
+        };
+
+        assert_eq!(offset, 4);
+        assert_eq!(snippet, "1 + + 2\n    ^");
+        assert!(matches!(*error, WgslError::NoExpression));
+
+        let Err(WgslError::InvalidSyntax { offset, .. }) = WgslExpression::new("  A   B") else {
+            panic!("`A B` should not parse");
+        };
+
+        assert_eq!(offset, 6);
+    }
+
+    #[test]
+    fn literals_compare_to_primitives() {
+        assert!(WgslLiteral::Integer(5) == 5i64);
+        assert!(WgslLiteral::Integer(5) != 5.0f64);
+        assert!(WgslLiteral::Float(5.0) == 5.0f64);
+        assert!(WgslLiteral::Float(5.0) != 5i64);
+        assert!(WgslLiteral::Bool(true) == true);
+        assert!(WgslLiteral::Integer(1) != true);
+        assert!(WgslLiteral::Str("a".to_string()) == "a");
+        assert_eq!(WgslLiteral::Integer(5), 5);
+    }
+
+    #[test]
+    fn parentheses_group_logical_operators() {
+        let state = state_with(&[
+            ("A", WgslLiteral::Bool(true)),
+            ("B", WgslLiteral::Bool(false)),
+            ("C", WgslLiteral::Bool(false)),
+        ]);
+
+        assert_eq!(state.eval_expr("(A || B) && C").unwrap(), false);
+        assert_eq!(state.eval_expr("A || (B && C)").unwrap(), true);
+        assert_eq!(state.eval_expr("A || B && C").unwrap(), true);
+        assert_eq!(state.eval_expr("C && B || A").unwrap(), true);
+        assert_eq!(state.eval_expr("C && (B || A)").unwrap(), false);
+
+        let grouped = WgslExpression::new("(A || B) && C").unwrap();
+        assert_eq!(grouped.to_source(), "(A || B) && C");
+    }
+
+    #[test]
+    fn between_is_half_open() {
+        let state = WgslWorkspaceState::default();
+
+        assert_eq!(state.eval_expr("between(5, 0, 10)").unwrap(), true);
+        assert_eq!(state.eval_expr("between(0, 0, 10)").unwrap(), true);
+        assert_eq!(state.eval_expr("between(10, 0, 10)").unwrap(), false);
+        assert_eq!(state.eval_expr("between(-1, 0, 10)").unwrap(), false);
+        assert_eq!(state.eval_expr("between(2.5, 0, 10)").unwrap(), true);
+        assert_eq!(state.eval_expr("between(5, 0.0, 4.5)").unwrap(), false);
+        assert!(state.eval_expr("between(5, 0)").is_err());
+    }
+
+    #[test]
+    fn shifts_check_their_amount() {
+        let state = WgslWorkspaceState::default();
+
+        assert_eq!(state.eval_expr("1 << 5").unwrap(), 32);
+        assert_eq!(state.eval_expr("-64 >> 3").unwrap(), -8);
+        assert!(matches!(
+            state.eval_expr("1 << 64"),
+            Err(WgslError::InvalidShift { amount: 64 })
+        ));
+        assert!(matches!(
+            state.eval_expr("1 << -1"),
+            Err(WgslError::InvalidShift { amount: -1 })
+        ));
+        assert!(matches!(
+            state.eval_expr("1 >> 64"),
+            Err(WgslError::InvalidShift { amount: 64 })
+        ));
+    }
+
+    #[test]
+    fn typecheck_only_rejects_definite_errors() {
+        for source in [
+            "true + 1",
+            "1 + 2.5",
+            "\"x\" < 2",
+            "(1 + 2) * false",
+            "!5 + 1",
+        ] {
+            let expression = WgslExpression::new(source).unwrap();
+            assert!(expression.typecheck().is_err(), "{source}");
+        }
+
+        for source in ["1 + 2", "X + 1", "X < 2 && Y", "f(true) + 1", "X ? 1 : 2"] {
+            let expression = WgslExpression::new(source).unwrap();
+            assert!(expression.typecheck().is_ok(), "{source}");
+        }
+    }
+
+    #[test]
+    fn unary_operators_need_an_operand() {
+        for (source, expected) in [("-", 1), ("!", 1), ("~", 1), ("- ", 2), ("1 + -", 5)] {
+            assert!(
+                matches!(
+                    WgslExpression::new(source),
+                    Err(WgslError::InvalidSyntax { offset, error, .. })
+                        if offset == expected && matches!(*error, WgslError::NoExpression)
+                ),
+                "{source}"
+            );
+        }
+
+        let state = WgslWorkspaceState::default();
+        assert_eq!(state.eval_expr("--5").unwrap(), 5);
+        assert_eq!(state.eval_expr("!!true").unwrap(), true);
+        assert_eq!(state.eval_expr("~~7").unwrap(), 7);
+    }
+
+    #[test]
+    fn only_exact_lowercase_bools_are_literals() {
+        assert_eq!(
+            WgslExpression::new("true").unwrap(),
+            WgslExpression::Literal(WgslLiteral::Bool(true))
+        );
+
+        for name in ["True", "TRUE", "true_flag", "truee", "false_"] {
+            assert_eq!(
+                WgslExpression::new(name).unwrap(),
+                WgslExpression::Reference(name.to_string())
+            );
+        }
+
+        let state = state_with(&[
+            ("True", WgslLiteral::Integer(1)),
+            ("true_flag", WgslLiteral::Bool(false)),
+            ("truee", WgslLiteral::Integer(2)),
+        ]);
+        assert_eq!(state.eval_expr("true").unwrap(), true);
+        assert_eq!(state.eval_expr("True").unwrap(), 1);
+        assert_eq!(state.eval_expr("true_flag").unwrap(), false);
+        assert_eq!(state.eval_expr("truee").unwrap(), 2);
+        assert!(state.eval_expr("TRUE").is_err());
+    }
+
+    #[test]
+    fn parentheses_keep_short_circuiting() {
+        let state = WgslWorkspaceState::default();
+
+        assert_eq!(
+            state.eval_expr("false && (UNDEFINED + 1 > 0)").unwrap(),
+            false
+        );
+        assert_eq!(
+            state.eval_expr("true || (UNDEFINED + 1 > 0)").unwrap(),
+            true
+        );
+        assert_eq!(state.eval_expr("(false) && ((UNDEFINED))").unwrap(), false);
+        assert_eq!(
+            state.eval_expr("(true || (UNDEFINED)) && true").unwrap(),
+            true
+        );
+
+        assert!(state.eval_expr("true && (UNDEFINED + 1 > 0)").is_err());
+        assert!(state.eval_expr("false || (UNDEFINED + 1 > 0)").is_err());
+    }
+
+    #[test]
+    fn adjacent_operands_are_unexpected_tokens() {
+        for (source, offset, token) in [("A B", 2, "B"), ("1 2", 2, "2"), ("(A) B", 4, "B")] {
+            assert!(
+                matches!(
+                    WgslExpression::new(source),
+                    Err(WgslError::InvalidSyntax { offset: found, error, .. })
+                        if found == offset
+                            && matches!(*error, WgslError::UnexpectedToken(ref t) if t == token)
+                ),
+                "{source}"
+            );
+        }
+
+        assert!(matches!(
+            WgslExpression::new("A &&"),
+            Err(WgslError::InvalidSyntax { offset: 4, error, .. })
+                if matches!(*error, WgslError::NoExpression)
+        ));
+    }
+
+    #[test]
+    fn only_numbers_can_be_ordered() {
+        let state = WgslWorkspaceState::default();
+
+        assert_eq!(state.eval_expr("3 < 2.5").unwrap(), false);
+        assert_eq!(state.eval_expr("2.5 > 3").unwrap(), false);
+        assert_eq!(state.eval_expr("3 >= 3.0").unwrap(), true);
+        assert_eq!(state.eval_expr("-1 <= 0.5").unwrap(), true);
+
+        for source in ["true < 5", "[1, 2] < 0", "\"a\" >= \"a\"", "false <= true"] {
+            assert!(
+                matches!(state.eval_expr(source), Err(WgslError::InvalidExpression)),
+                "{source}"
+            );
+
+            let expression = WgslExpression::new(source).unwrap();
+            assert!(expression.typecheck().is_err(), "{source}");
+        }
+    }
+}
@@ -1,345 +1,4869 @@
+#[cfg(feature = "serde")]
+mod config;
 pub mod expression;
+#[cfg(feature = "serde")]
+mod snapshot;
+#[cfg(feature = "sync")]
+mod sync;
 
 use std::{
-    collections::HashMap,
+    borrow::Cow,
+    collections::{HashMap, HashSet},
     num::{ParseFloatError, ParseIntError},
-    path::PathBuf,
+    path::{Component, Path, PathBuf},
 };
 
-use expression::{WgslExpression, WgslLiteral};
+#[cfg(feature = "sync")]
+pub use sync::SyncWgslWorkspace;
 
-#[derive(Debug)]
+use expression::{
+    split_doc_comment, IntOverflow, WgslExpression, WgslFunction, WgslInferredType, WgslLiteral,
+    WgslSuffix, BUILTIN_FUNCTIONS,
+};
+
+#[derive(Debug, PartialEq, Eq)]
 pub enum WgslSegmentEndReason {
     None,
     EndOfFile,
-    ElseOp,
-    EndOp,
+    ElseOp(usize),
+    /// `//: end`, with the label of `//: end if` or `//: end for`
+    EndOp(usize, Option<String>),
+    EndSectionOp(usize),
 }
 
-#[derive(Debug, Clone)]
-pub enum WgslSegment {
-    Include(PathBuf),
-    Conditional {
-        condition: WgslExpression,
-        if_true: Box<WgslSegment>,
-        if_false: Option<Box<WgslSegment>>,
+impl WgslSegmentEndReason {
+    /// Errors if this is a labeled `//: end` that doesn't close a `kind`
+    /// block, a plain `//: end` closes any block.
+    fn check_end_label(&self, kind: &str) -> Result<(), WgslError> {
+        match self {
+            WgslSegmentEndReason::EndOp(line, Some(label)) if label != kind => {
+                Err(WgslError::MismatchedEnd {
+                    expected: kind.to_string(),
+                    found: label.clone(),
+                    line: *line,
+                })
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// What a `//: for` loop iterates over
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum WgslLoopTarget {
+    /// `start..end`, `end` is exclusive
+    Range {
+        start: WgslExpression,
+        end: WgslExpression,
     },
-    Sequence(Vec<WgslSegment>),
-    Constant(String),
-    Text(String),
+    /// Every element of a list in order
+    List(WgslExpression),
 }
 
-impl WgslSegment {
-    pub fn write(&self, output: &mut String, workspace: &WgslWorkspace) -> Result<(), WgslError> {
+impl WgslLoopTarget {
+    fn new(source: &str) -> Result<Self, WgslError> {
+        match source.split_once("..") {
+            Some((start, end)) => Ok(Self::Range {
+                start: WgslExpression::new(start)?,
+                end: WgslExpression::new(end)?,
+            }),
+            None => Ok(Self::List(WgslExpression::new(source)?)),
+        }
+    }
+
+    /// Evaluates the values the loop variable takes, at most `limit` values.
+    fn values(
+        &self,
+        state: &WgslWorkspaceState,
+        limit: usize,
+    ) -> Result<Vec<WgslLiteral>, WgslError> {
         match self {
-            WgslSegment::Include(i) => {
-                output.push_str(&workspace.get_shader(i)?);
-                output.push('\n');
-            }
-            WgslSegment::Conditional {
-                condition,
-                if_true,
-                if_false,
-            } => {
-                let is_true = match condition.evaluate(workspace.state())? {
-                    WgslLiteral::Integer(i) => i != 0,
-                    WgslLiteral::Float(f) => f != 0.0,
-                    WgslLiteral::Bool(b) => b,
+            Self::Range { start, end } => {
+                let (WgslLiteral::Integer(start), WgslLiteral::Integer(end)) =
+                    (start.evaluate(state)?, end.evaluate(state)?)
+                else {
+                    Err(WgslError::InvalidExpression)?
                 };
 
-                if is_true {
-                    if_true.write(output, workspace)?;
-                } else if let Some(if_false) = if_false.as_ref() {
-                    if_false.write(output, workspace)?;
-                }
-            }
-            WgslSegment::Sequence(sequence) => {
-                for segment in sequence.iter() {
-                    segment.write(output, workspace)?;
+                let count = usize::try_from(end as i128 - start as i128).unwrap_or(0);
+
+                if count > limit {
+                    Err(WgslError::LoopTooLarge { count, limit })?;
                 }
+
+                Ok((start..end).map(WgslLiteral::Integer).collect())
             }
-            WgslSegment::Constant(name) => {
-                let value = workspace
-                    .state()
-                    .get(name)
-                    .ok_or(WgslError::UndefinedVariable)?;
+            Self::List(list) => match list.evaluate(state)? {
+                WgslLiteral::List(items) if items.len() > limit => Err(WgslError::LoopTooLarge {
+                    count: items.len(),
+                    limit,
+                }),
+                WgslLiteral::List(items) => Ok(items),
+                other => Err(WgslError::NotIterable(other)),
+            },
+        }
+    }
 
-                match value {
-                    WgslLiteral::Integer(i) => output.push_str(&format!("const {name} = {i};\n")),
-                    WgslLiteral::Float(f) => output.push_str(&format!("const {name} = {f};\n")),
-                    WgslLiteral::Bool(b) => output.push_str(&format!("const {name} = {b};\n")),
-                }
+    fn visit_typed_references(&self, visitor: &mut dyn FnMut(&str, WgslInferredType)) {
+        match self {
+            Self::Range { start, end } => {
+                start.visit_typed_references(WgslInferredType::Integer, visitor);
+                end.visit_typed_references(WgslInferredType::Integer, visitor);
             }
-            WgslSegment::Text(t) => output.push_str(t),
+            Self::List(list) => list.visit_typed_references(WgslInferredType::List, visitor),
         }
+    }
 
-        Ok(())
+    fn depends_on(&self, variable: &str) -> bool {
+        match self {
+            Self::Range { start, end } => start.depends_on(variable) || end.depends_on(variable),
+            Self::List(list) => list.depends_on(variable),
+        }
     }
 
-    pub fn from_lines<'a>(
-        lines: &mut impl Iterator<Item = &'a str>,
-    ) -> Result<(Option<Self>, WgslSegmentEndReason), WgslError> {
-        let mut segment = WgslSegment::Text(String::new());
+    fn to_source(&self) -> String {
+        match self {
+            Self::Range { start, end } => format!("{}..{}", start.to_source(), end.to_source()),
+            Self::List(list) => list.to_source(),
+        }
+    }
+}
+
+/// Segments are equal when they have the same structure, see
+/// [`WgslLiteral`] for how floats are compared.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum WgslSegment {
+    Include {
+        path: PathBuf,
+        /// Only this `//: section` of the shader is included, written as
+        /// `path#section`
+        section: Option<String>,
+        /// Prepended to the names of constants in the included shader
+        prefix: Option<String>,
+    },
+    /// `//: include_glob effects/*.wgsl`, includes every matching shader in
+    /// sorted order
+    IncludeGlob(PathBuf),
+    Conditional {
+        condition: WgslExpression,
+        if_true: Box<WgslSegment>,
+        if_false: Option<Box<WgslSegment>>,
+    },
+    /// `{{#if condition}}...{{#else}}...{{/if}}` inside a single line of
+    /// text, see [`WgslSegment::Conditional`]
+    InlineConditional {
+        condition: WgslExpression,
+        if_true: Box<WgslSegment>,
+        if_false: Option<Box<WgslSegment>>,
+    },
+    Loop {
+        variable: String,
+        target: WgslLoopTarget,
+        body: Box<WgslSegment>,
+    },
+    Sequence(Vec<WgslSegment>),
+    /// A named part of a shader that can be included on its own
+    Section {
+        name: String,
+        body: Box<WgslSegment>,
+    },
+    Constant {
+        name: String,
+        /// Written as a `// comment` above the constant, set by a trailing
+        /// `/// comment`
+        doc: Option<String>,
+    },
+    ConstantExpression {
+        name: String,
+        ty: Option<String>,
+        /// Digits after the decimal point of floats, set by `f32(6)`
+        precision: Option<usize>,
+        expression: WgslExpression,
+        /// Same as the `doc` of [`WgslSegment::Constant`]
+        doc: Option<String>,
+    },
+    /// A `//: consts` block, later constants can use the earlier ones
+    Constants(Vec<(String, WgslExpression)>),
+    /// `//: export NAME expression`, sets a variable for the rest of the
+    /// shader and for the shader that includes it, without writing anything
+    Export {
+        name: String,
+        expression: WgslExpression,
+    },
+    /// `//: default NAME expression`, same as [`WgslSegment::Export`] but
+    /// only when `NAME` isn't set yet, the workspace can still override it
+    Default {
+        name: String,
+        expression: WgslExpression,
+    },
+    /// `//: binding auto` or `//: binding NAME`, writes
+    /// `@group(G) @binding(B)` with the group set by the last
+    /// [`WgslSegment::Group`]. `auto` (`None`) takes the next free index,
+    /// `NAME` uses the `NAME_BINDING` variable in uppercase.
+    Binding(Option<String>),
+    /// `//: group expression`, the group of the bindings after it, which
+    /// also starts `//: binding auto` at 0 again
+    Group(WgslExpression),
+    Pragma {
+        key: String,
+        value: String,
+    },
+    Substitution {
+        expression: WgslExpression,
+        /// Set by `${expression:suffix}`
+        suffix: Option<WgslSuffix>,
+    },
+    Text(String),
+}
 
-        while let Some(line) = lines.next() {
-            let line = line.trim();
+/// The tags of a [`WgslSegment::InlineConditional`].
+const INLINE_IF: &str = "{{#if ";
+const INLINE_ELSE: &str = "{{#else}}";
+const INLINE_END: &str = "{{/if}}";
 
-            if !line.starts_with("//:") {
-                segment.concat(Self::Text(format!("{line}\n")));
-                continue;
-            }
+/// The longest name a variable or constant can have.
+pub const MAX_IDENTIFIER_LENGTH: usize = 256;
 
-            let line = line[3..].to_owned();
+/// WGSL keywords, these can't be used as the name of a written constant.
+pub const WGSL_KEYWORDS: &[&str] = &[
+    "alias",
+    "break",
+    "case",
+    "const",
+    "const_assert",
+    "continue",
+    "continuing",
+    "default",
+    "diagnostic",
+    "discard",
+    "else",
+    "enable",
+    "false",
+    "fn",
+    "for",
+    "if",
+    "let",
+    "loop",
+    "override",
+    "requires",
+    "return",
+    "struct",
+    "switch",
+    "true",
+    "var",
+    "while",
+];
 
-            let (operation, parameter) = line.split_once(' ').unwrap_or((&line, ""));
+/// Checks that `name` can be used as a WGSL identifier, `_` and names starting
+/// with `__` are reserved by WGSL.
+fn validate_identifier(name: &str) -> Result<(), WgslError> {
+    let mut chars = name.chars();
 
-            match operation {
-                "include" => segment.concat(WgslSegment::Include(parameter.into())),
-                "const" => segment.concat(WgslSegment::Constant(parameter.into())),
-                "if" => {
-                    let condition = WgslExpression::new(parameter)?;
+    let valid = chars
+        .next()
+        .is_some_and(|ch| ch.is_ascii_alphabetic() || ch == '_')
+        && chars.all(|ch| ch.is_ascii_alphanumeric() || ch == '_')
+        && name.len() <= MAX_IDENTIFIER_LENGTH
+        && name != "_"
+        && !name.starts_with("__");
 
-                    let (if_true, if_false) = match WgslSegment::from_lines(lines)? {
-                        (Some(segment), WgslSegmentEndReason::ElseOp) => (
-                            Box::new(segment),
-                            Some(Box::new(
-                                WgslSegment::from_lines(lines)?
-                                    .0
-                                    .ok_or(WgslError::InvalidIfBlock)?,
-                            )),
-                        ),
-                        (
-                            Some(segment),
-                            WgslSegmentEndReason::EndOp | WgslSegmentEndReason::EndOfFile,
-                        ) => (Box::new(segment), None),
-                        _ => Err(WgslError::InvalidIfBlock)?,
-                    };
+    if valid {
+        Ok(())
+    } else {
+        Err(WgslError::InvalidIdentifier(name.to_string()))
+    }
+}
 
-                    segment.concat(WgslSegment::Conditional {
-                        condition,
-                        if_true,
-                        if_false,
-                    });
-                }
-                "else" => return Ok((Some(segment), WgslSegmentEndReason::ElseOp)),
-                "end" => return Ok((Some(segment), WgslSegmentEndReason::EndOp)),
-                other => Err(WgslError::UnknownOperation(other.to_string()))?,
-            }
-        }
+/// Same as [`validate_identifier`] but also rejects [`WGSL_KEYWORDS`], for
+/// names that are written to the shader.
+fn validate_constant_name(name: &str) -> Result<(), WgslError> {
+    validate_identifier(name)?;
 
-        Ok((Some(segment), WgslSegmentEndReason::EndOfFile))
+    if WGSL_KEYWORDS.contains(&name) {
+        Err(WgslError::InvalidIdentifier(name.to_string()))
+    } else {
+        Ok(())
     }
+}
 
-    #[inline]
-    pub fn can_concat_fast(&self, other: &WgslSegment) -> bool {
-        matches!(
-            (self, other),
-            (_, WgslSegment::Sequence(_))
-                | (WgslSegment::Sequence(_), _)
-                | (WgslSegment::Text(_), WgslSegment::Text(_))
-        )
+/// The value of a `//: group` or `NAME_BINDING` as an index.
+fn binding_index(value: WgslLiteral) -> Result<u32, WgslError> {
+    match value {
+        WgslLiteral::Integer(index) => {
+            u32::try_from(index).map_err(|_| WgslError::InvalidBinding(value))
+        }
+        value => Err(WgslError::InvalidBinding(value)),
     }
+}
 
-    pub fn concat(&mut self, other: WgslSegment) {
-        match (self, other) {
-            (WgslSegment::Sequence(left), WgslSegment::Sequence(mut right)) => {
-                left.reserve(right.len());
+/// Matches a single path component against a pattern where `*` matches any
+/// number of characters and `?` matches one character.
+///
+/// On a mismatch only the last `*` is retried with one more character, which
+/// takes at most `pattern.len() * name.len()` steps for any pattern.
+fn glob_matches(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
 
-                for segment in right.drain(..) {
-                    if left.last().is_some_and(|l| l.can_concat_fast(&segment)) {
-                        unsafe { left.last_mut().unwrap_unchecked() }.concat(segment);
-                    } else {
-                        left.push(segment);
-                    }
-                }
+    let (mut p, mut n) = (0, 0);
+    // The position after the last `*` and the character it matches up to
+    let mut star = None;
+
+    while n < name.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                p += 1;
+                star = Some((p, n));
             }
-            (WgslSegment::Text(left), WgslSegment::Text(right)) => {
-                left.push_str(&right);
+            Some(&ch) if ch == '?' || ch == name[n] => {
+                p += 1;
+                n += 1;
             }
-            (left, mut right @ WgslSegment::Sequence(_)) => {
-                // The following is a safe version of:
-                // right.insert(0, left);
-                // left = right;
+            _ => match star {
+                Some((after_star, matched)) => {
+                    p = after_star;
+                    n = matched + 1;
+                    star = Some((after_star, n));
+                }
+                None => return false,
+            },
+        }
+    }
 
-                core::mem::swap(left, &mut right);
+    pattern[p..].iter().all(|&ch| ch == '*')
+}
 
-                // Values now swapped so we swap names as well
-                let (left, right) = (right, left);
+/// Adds the line an error was found on, see [`WgslError::InLine`].
+fn in_line(line: usize, source: &str) -> impl FnOnce(WgslError) -> WgslError + '_ {
+    move |error| WgslError::InLine {
+        line,
+        source: source.to_string(),
+        error: Box::new(error),
+    }
+}
 
-                match right {
-                    WgslSegment::Sequence(sequence) => sequence.insert(0, left),
-                    _ => unreachable!(),
-                }
-            }
-            (WgslSegment::Sequence(ref mut sequence), right) => match sequence.last_mut() {
-                Some(segment) if segment.can_concat_fast(&right) => segment.concat(right),
-                _ => sequence.push(right),
-            },
-            (left, right) => {
-                // The following is basically a version of this but written in
-                // a way that the Rust compiler won't complain:
-                // *self = WgslSegment::Sequence(vec![left, right])
+/// Converts `\` to `/` so shaders can be found with either separator, e.g.
+/// `lib\math.wgsl` is the same shader as `lib/math.wgsl`.
+fn slash_path(path: impl Into<PathBuf>) -> PathBuf {
+    let path = path.into();
 
-                let mut sequence = WgslSegment::Sequence(Vec::new());
-                core::mem::swap(left, &mut sequence);
+    match path.to_str() {
+        Some(text) if text.contains('\\') => PathBuf::from(text.replace('\\', "/")),
+        _ => path,
+    }
+}
 
-                // Values now swapped so we swap names as well
-                let (sequence, left) = (left, sequence);
+/// Returns `true` for absolute paths and for Windows paths with a drive
+/// letter like `C:/shaders`, which are not absolute on other platforms.
+fn is_absolute_path(path: &Path) -> bool {
+    let bytes = path.as_os_str().as_encoded_bytes();
 
-                match sequence {
-                    WgslSegment::Sequence(ref mut sequence) => {
-                        sequence.push(left);
-                        sequence.push(right);
-                    }
-                    _ => unreachable!(),
-                }
+    path.is_absolute()
+        || matches!(bytes, [drive, b':', b'/' | b'\\', ..] if drive.is_ascii_alphabetic())
+}
+
+/// FNV-1a of a shader's source, to tell if a shader restored with
+/// `WgslWorkspace::from_cache_bytes` is outdated. Unlike `DefaultHasher` this
+/// is the same in every build.
+fn source_hash(source: &str) -> u64 {
+    source.bytes().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x100000001b3)
+    })
+}
+
+/// Removes `.` and `..` from a path without touching the file system.
+fn normalize_path(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+
+    for component in path.components() {
+        match component {
+            Component::CurDir => (),
+            Component::ParentDir if normalized.file_name().is_some() => {
+                normalized.pop();
             }
+            component => normalized.push(component),
         }
     }
-}
 
-#[derive(Debug)]
-pub struct WgslShader {
-    segment: WgslSegment,
-    capacity: usize,
+    normalized
 }
 
-impl WgslShader {
-    pub fn new(source: &str) -> Result<Self, WgslError> {
-        let capacity = source.len();
-        let mut lines = source.lines().filter(|l| !l.trim().is_empty());
-        let segment = WgslSegment::from_lines(&mut lines)?
-            .0
-            .unwrap_or(WgslSegment::Text(String::new()));
+/// Converts `value` to WGSL, strings only exist inside the preprocessor.
+fn to_wgsl(value: &WgslLiteral, precision: Option<usize>) -> Result<String, WgslError> {
+    if value.contains_string() {
+        Err(WgslError::EmittedString)
+    } else if !value.is_finite() {
+        Err(WgslError::NonFiniteFloat(value.clone()))
+    } else if let Some(precision) = precision.filter(|&p| value.rounds_to_zero(p)) {
+        Err(WgslError::RoundedToZero {
+            value: value.clone(),
+            precision,
+        })
+    } else {
+        Ok(value.to_wgsl_string_with_precision(precision))
+    }
+}
 
-        if lines.clone().next().is_some() {
-            Err(WgslError::LeftoverChars(lines.collect()))?;
-        }
+fn write_constant(
+    output: &mut String,
+    name: &str,
+    ty: Option<&str>,
+    precision: Option<usize>,
+    value: &WgslLiteral,
+) -> Result<(), WgslError> {
+    let value = to_wgsl(value, precision)?;
 
-        Ok(Self { segment, capacity })
+    match ty {
+        Some(ty) => output.push_str(&format!("const {name}: {ty} = {value};")),
+        None => output.push_str(&format!("const {name} = {value};")),
     }
 
-    fn evaluate(&self, workspace: &WgslWorkspace) -> Result<String, WgslError> {
-        let mut result = String::with_capacity(self.capacity);
+    end_line(output);
 
-        self.segment.write(&mut result, workspace)?;
+    Ok(())
+}
 
-        Ok(result)
+fn write_doc_comment(output: &mut String, doc: Option<&str>) {
+    if let Some(doc) = doc {
+        output.push_str(&format!("// {doc}\n"));
     }
 }
 
-#[derive(Debug, Clone)]
-pub struct WgslWorkspaceState {
-    global_variables: HashMap<String, WgslLiteral>,
-    local_overrides: HashMap<String, WgslLiteral>,
+/// Ends a directive written by [`WgslSegment::write_source`] with its
+/// `/// doc comment`, if any.
+fn write_doc_source(output: &mut String, doc: Option<&str>) {
+    if let Some(doc) = doc {
+        output.push_str(&format!(" /// {doc}"));
+    }
+
+    output.push('\n');
 }
 
-impl WgslWorkspaceState {
-    pub fn get(&self, key: &str) -> Option<WgslLiteral> {
-        self.local_overrides
-            .get(key)
-            .copied()
-            .or(self.global_variables.get(key).copied())
+/// Ends the last line of `output` if it isn't ended yet. Directives that
+/// write whole lines use this instead of always adding a newline, so
+/// empty includes don't leave blank lines and nothing is ever ended twice.
+fn end_line(output: &mut String) {
+    if !output.is_empty() && !output.ends_with('\n') {
+        output.push('\n');
     }
 }
 
-impl Default for WgslWorkspaceState {
-    fn default() -> Self {
-        let local_overrides = HashMap::new();
-        let mut global_variables = HashMap::new();
+/// Splits a WGSL type like `array<f32, 5>` from the start of `source`.
+fn split_type(source: &str) -> Result<(&str, &str), WgslError> {
+    let mut depth = 0;
 
-        for i in 0..64 {
-            global_variables.insert(format!("BIT_{i}"), WgslLiteral::Integer(1 << i));
+    for (i, ch) in source.char_indices() {
+        match ch {
+            '<' => depth += 1,
+            '>' if depth > 0 => {
+                depth -= 1;
+
+                if depth == 0 {
+                    return Ok(source.split_at(i + 1));
+                }
+            }
+            ch if depth == 0 && !(ch.is_ascii_alphanumeric() || ch == '_') => {
+                return Ok(source.split_at(i));
+            }
+            _ => (),
         }
+    }
 
-        global_variables.insert("PI".into(), WgslLiteral::Float(core::f64::consts::PI));
+    if depth == 0 {
+        Ok((source, ""))
+    } else {
+        Err(WgslError::InvalidType(source.to_string()))
+    }
+}
 
-        Self {
-            global_variables,
-            local_overrides,
+/// Finds the first `pattern` that is not inside a string literal, e.g. the
+/// `}` that ends `${"a}b"}`.
+fn find_outside_strings(source: &str, pattern: &str) -> Option<usize> {
+    let mut in_string = false;
+    let mut escaped = false;
+
+    for (i, ch) in source.char_indices() {
+        match ch {
+            _ if escaped => escaped = false,
+            '\\' if in_string => escaped = true,
+            '"' => in_string = !in_string,
+            _ if !in_string && source[i..].starts_with(pattern) => return Some(i),
+            _ => (),
         }
     }
+
+    None
 }
 
-#[derive(Debug)]
-pub struct WgslWorkspace {
-    state: WgslWorkspaceState,
-    root: PathBuf,
-    shaders: HashMap<PathBuf, WgslShader>,
+/// Finds the first `${` or inline conditional tag in `text`. This only reads
+/// `text` up to the tag, so a line with many substitutions is parsed in
+/// linear time.
+fn find_inline_tag(text: &str) -> Option<(usize, &'static str)> {
+    text.match_indices(['$', '{']).find_map(|(i, _)| {
+        ["${", INLINE_IF, INLINE_ELSE, INLINE_END]
+            .into_iter()
+            .find(|tag| text[i..].starts_with(tag))
+            .map(|tag| (i, tag))
+    })
 }
 
-impl WgslWorkspace {
-    pub fn scan(root: impl Into<PathBuf>) -> Self {
-        let shaders = HashMap::new();
+/// See [`WgslWorkspace::get_shader_with_constants_cb`]
+type ConstantCallback<'a> = &'a mut dyn FnMut(&str, WgslLiteral);
 
-        Self {
-            state: WgslWorkspaceState::default(),
-            root: root.into(),
-            shaders,
-        }
-    }
+/// What a line is to [`WgslSegment::walk_lines`].
+enum WgslLineKind {
+    Text,
+    Directive,
+    /// The `//: if` of a block whose branch is known, and whether the first
+    /// branch is taken
+    Condition(bool),
+    /// The `//: else` or `//: end` of a block whose branch is known
+    ConditionEnd,
+    /// A `//: for` and how many times its body is walked
+    Loop(usize),
+    /// An `//: include` or `//: include_glob`
+    Include,
+}
 
-    /// - `root`: The root of the workspace
-    /// - `shaders`: A list of shaders `(path, source)`, path is relative to
-    /// `root`
-    pub fn from_memory(
-        root: impl Into<PathBuf>,
-        shaders: &[(&str, &str)],
-    ) -> Result<Self, WgslError> {
-        let shaders = shaders
-            .iter()
-            .map(|(path, source)| Ok((path.into(), WgslShader::new(source)?)))
-            .collect::<Result<_, _>>()?;
+/// Decides which branches [`WgslSegment::walk_lines`] takes and is given
+/// every line it walks.
+trait WgslLineVisitor {
+    /// The branch a taken `//: if` takes, or `None` to walk both
+    fn branch(&mut self, condition: &WgslExpression) -> Option<bool>;
+    /// How many times the body of a taken `//: for` is walked
+    fn iterations(&mut self) -> usize;
+    fn visit(&mut self, line: (usize, bool), kind: WgslLineKind, taken: bool);
+}
 
-        Ok(Self {
-            state: WgslWorkspaceState::default(),
-            root: root.into(),
-            shaders,
-        })
-    }
+/// Traces the lines of a shader from the decisions recorded while writing
+/// it, see [`WgslWorkspace::trace_shader`].
+struct WgslTraceVisitor {
+    decisions: std::vec::IntoIter<WgslLineAction>,
+    trace: Vec<WgslLineTrace>,
+}
 
-    pub fn set_global_i64(&mut self, key: &str, value: i64) {
-        self.state
-            .global_variables
-            .insert(key.to_string(), WgslLiteral::Integer(value));
+impl WgslLineVisitor for WgslTraceVisitor {
+    fn branch(&mut self, _: &WgslExpression) -> Option<bool> {
+        // Every taken `//: if` was written, in the same order
+        match self.decisions.next() {
+            Some(WgslLineAction::Condition(is_true)) => Some(is_true),
+            _ => None,
+        }
     }
 
-    pub fn set_global_f64(&mut self, key: &str, value: f64) {
-        self.state
-            .global_variables
-            .insert(key.to_string(), WgslLiteral::Float(value));
+    fn iterations(&mut self) -> usize {
+        match self.decisions.next() {
+            Some(WgslLineAction::Loop(iterations)) => iterations,
+            _ => 0,
+        }
     }
 
-    pub fn set_global_bool(&mut self, key: &str, value: bool) {
-        self.state
-            .global_variables
-            .insert(key.to_string(), WgslLiteral::Bool(value));
+    fn visit(&mut self, (line, _): (usize, bool), kind: WgslLineKind, taken: bool) {
+        let action = match (taken, kind) {
+            (false, _) => WgslLineAction::Skipped,
+            (true, WgslLineKind::Text) => WgslLineAction::Text,
+            (true, WgslLineKind::Directive | WgslLineKind::ConditionEnd) => {
+                WgslLineAction::Directive
+            }
+            (true, WgslLineKind::Condition(is_true)) => WgslLineAction::Condition(is_true),
+            (true, WgslLineKind::Loop(iterations)) => WgslLineAction::Loop(iterations),
+            (true, WgslLineKind::Include) => {
+                self.decisions.next().unwrap_or(WgslLineAction::Directive)
+            }
+        };
+
+        self.trace.push(WgslLineTrace { line, action });
+    }
+}
+
+/// Keeps the lines that are still part of the tree after
+/// [`WgslSegment::fold_conditions`], the ones of `//: if` blocks whose
+/// condition doesn't use any variable or function are left out.
+struct WgslFoldVisitor {
+    kept: Vec<(usize, bool)>,
+}
+
+impl WgslLineVisitor for WgslFoldVisitor {
+    fn branch(&mut self, condition: &WgslExpression) -> Option<bool> {
+        condition.constant_fold()?.is_truthy().ok()
+    }
+
+    fn iterations(&mut self) -> usize {
+        1
+    }
+
+    fn visit(&mut self, line: (usize, bool), kind: WgslLineKind, taken: bool) {
+        if taken
+            && !matches!(
+                kind,
+                WgslLineKind::Condition(_) | WgslLineKind::ConditionEnd
+            )
+        {
+            self.kept.push(line);
+        }
+    }
+}
+
+/// Gives the next line to `visitor`.
+fn walk_line(
+    lines: &mut std::slice::Iter<(usize, bool)>,
+    kind: WgslLineKind,
+    taken: bool,
+    visitor: &mut dyn WgslLineVisitor,
+) {
+    if let Some(line) = lines.next() {
+        visitor.visit(*line, kind, taken);
+    }
+}
+
+/// Everything that is collected while writing a shader, shared between the
+/// shader and the shaders it includes.
+struct WgslWriteContext<'a> {
+    workspace: &'a WgslWorkspace,
+    /// The shader that is currently being written
+    path: PathBuf,
+    /// Set when inside an `//: include <path> as <prefix>`
+    prefix: Option<String>,
+    /// Only cloned from the workspace once a local variable is set
+    state: Cow<'a, WgslWorkspaceState>,
+    pragmas: HashMap<String, String>,
+    warnings: Vec<WgslWarning>,
+    /// Called with the name and value of every constant that is written
+    on_constant: Option<ConstantCallback<'a>>,
+    /// Set by `//: group`, shared with included shaders like the variables
+    group: u32,
+    /// The index `//: binding auto` writes next
+    next_binding: u32,
+    /// Set by [`WgslWorkspace::trace_shader`], every `//: if`, `//: for` and
+    /// `//: include` of the traced shader adds what it did, included shaders
+    /// add nothing
+    decisions: Option<Vec<WgslLineAction>>,
+}
+
+impl<'a> WgslWriteContext<'a> {
+    fn new(workspace: &'a WgslWorkspace) -> Self {
+        Self {
+            workspace,
+            path: PathBuf::new(),
+            prefix: None,
+            state: Cow::Borrowed(workspace.state()),
+            pragmas: HashMap::new(),
+            warnings: Vec::new(),
+            on_constant: None,
+            group: 0,
+            next_binding: 0,
+            decisions: None,
+        }
     }
 
     fn state(&self) -> &WgslWorkspaceState {
         &self.state
     }
 
-    pub fn get_shader(&self, path: impl Into<PathBuf>) -> Result<String, WgslError> {
-        self.shaders
-            .get(&path.into())
-            .ok_or(WgslError::NotFound)?
-            .evaluate(self)
+    /// The name a constant is emitted with
+    fn prefixed(&self, name: &str) -> String {
+        match self.prefix.as_ref() {
+            Some(prefix) => format!("{prefix}_{name}"),
+            None => name.to_string(),
+        }
+    }
+
+    fn write_constant(
+        &mut self,
+        output: &mut String,
+        name: &str,
+        ty: Option<&str>,
+        precision: Option<usize>,
+        value: &WgslLiteral,
+    ) -> Result<(), WgslError> {
+        write_constant(output, name, ty, precision, value)?;
+
+        if let Some(on_constant) = self.on_constant.as_mut() {
+            on_constant(name, value.clone());
+        }
+
+        Ok(())
+    }
+
+    /// Sets a local variable, returning its previous value so it can be
+    /// restored with [`WgslWriteContext::restore_local`].
+    fn set_local(&mut self, key: &str, value: WgslLiteral) -> Option<WgslLiteral> {
+        self.state
+            .to_mut()
+            .local_overrides
+            .insert(key.to_string(), value)
+    }
+
+    fn record(&mut self, decision: WgslLineAction) {
+        if let Some(decisions) = self.decisions.as_mut() {
+            decisions.push(decision);
+        }
+    }
+
+    fn restore_local(&mut self, key: &str, previous: Option<WgslLiteral>) {
+        let local_overrides = &mut self.state.to_mut().local_overrides;
+
+        match previous {
+            Some(previous) => local_overrides.insert(key.to_string(), previous),
+            None => local_overrides.remove(key),
+        };
     }
 }
 
-#[derive(Debug, Clone)]
-pub enum WgslError {
-    UnknownOperation(String),
-    InvalidIfBlock,
-    NoExpression,
-    NoClosingParenthesis,
-    DuplicatePeriod,
-    InvalidBase,
-    ParseFloatError(ParseFloatError),
-    ParseIntError(ParseIntError),
-    LeftoverChars(String),
-    UndefinedVariable,
-    InvalidExpression,
-    NotFound,
+impl WgslSegment {
+    pub fn write(&self, output: &mut String, workspace: &WgslWorkspace) -> Result<(), WgslError> {
+        self.write_with(output, &mut WgslWriteContext::new(workspace))
+    }
+
+    fn write_with(
+        &self,
+        output: &mut String,
+        context: &mut WgslWriteContext,
+    ) -> Result<(), WgslError> {
+        match self {
+            WgslSegment::Include {
+                path,
+                section,
+                prefix,
+            } => {
+                let workspace = context.workspace;
+                let path = workspace.resolve_include(&context.path, path)?;
+                context.record(WgslLineAction::Included(path.clone()));
+
+                let parent_prefix = context.prefix.clone();
+                let decisions = context.decisions.take();
+
+                if let Some(prefix) = prefix {
+                    context.prefix = Some(match parent_prefix.as_ref() {
+                        Some(parent_prefix) => format!("{parent_prefix}_{prefix}"),
+                        None => prefix.clone(),
+                    });
+                }
+
+                let result = workspace.render_into(path, section.as_deref(), context, output);
+                context.prefix = parent_prefix;
+                context.decisions = decisions;
+
+                result?;
+                end_line(output);
+            }
+            WgslSegment::IncludeGlob(pattern) => {
+                let workspace = context.workspace;
+                let paths = workspace.resolve_include_glob(&context.path, pattern);
+                context.record(WgslLineAction::IncludedGlob(paths.clone()));
+
+                let decisions = context.decisions.take();
+                let result = paths.into_iter().try_for_each(|path| {
+                    workspace.render_into(path, None, context, output)?;
+                    end_line(output);
+                    Ok(())
+                });
+                context.decisions = decisions;
+
+                result?;
+            }
+            WgslSegment::Conditional {
+                condition,
+                if_true,
+                if_false,
+            } => {
+                let is_true = condition.evaluate(context.state())?.is_truthy()?;
+                context.record(WgslLineAction::Condition(is_true));
+
+                if is_true {
+                    if_true.write_with(output, context)?;
+                } else if let Some(if_false) = if_false.as_ref() {
+                    if_false.write_with(output, context)?;
+                }
+            }
+            WgslSegment::InlineConditional {
+                condition,
+                if_true,
+                if_false,
+            } => {
+                let is_true = condition.evaluate(context.state())?.is_truthy()?;
+
+                if is_true {
+                    if_true.write_with(output, context)?;
+                } else if let Some(if_false) = if_false.as_ref() {
+                    if_false.write_with(output, context)?;
+                }
+            }
+            WgslSegment::Loop {
+                variable,
+                target,
+                body,
+            } => {
+                let values = target.values(context.state(), context.workspace.max_unroll)?;
+                context.record(WgslLineAction::Loop(values.len()));
+
+                let previous = context
+                    .state()
+                    .local_overrides
+                    .get(variable.as_str())
+                    .cloned();
+
+                for value in values {
+                    context.set_local(variable, value);
+                    body.write_with(output, context)?;
+                }
+
+                context.restore_local(variable, previous);
+            }
+            WgslSegment::Sequence(sequence) => {
+                for segment in sequence.iter() {
+                    segment.write_with(output, context)?;
+                }
+            }
+            WgslSegment::Section { body, .. } => body.write_with(output, context)?,
+            WgslSegment::Constant { name, doc } => {
+                let emitted_name = context.prefixed(name);
+                validate_constant_name(&emitted_name)?;
+
+                let value = context
+                    .state()
+                    .get(name)
+                    .ok_or(WgslError::UndefinedVariable)?;
+
+                write_doc_comment(output, doc.as_deref());
+                context.write_constant(output, &emitted_name, None, None, &value)?;
+            }
+            WgslSegment::ConstantExpression {
+                name,
+                ty,
+                precision,
+                expression,
+                doc,
+            } => {
+                let emitted_name = context.prefixed(name);
+                validate_constant_name(&emitted_name)?;
+
+                let value =
+                    expression.evaluate_with_warnings(&context.state, &mut context.warnings)?;
+
+                write_doc_comment(output, doc.as_deref());
+                context.write_constant(output, &emitted_name, ty.as_deref(), *precision, &value)?;
+            }
+            WgslSegment::Constants(constants) => {
+                let mut previous = Vec::with_capacity(constants.len());
+
+                for (name, expression) in constants.iter() {
+                    let emitted_name = context.prefixed(name);
+                    validate_constant_name(&emitted_name)?;
+
+                    let value =
+                        expression.evaluate_with_warnings(&context.state, &mut context.warnings)?;
+
+                    context.write_constant(output, &emitted_name, None, None, &value)?;
+                    previous.push((name, context.set_local(name, value)));
+                }
+
+                for (name, previous) in previous.into_iter().rev() {
+                    context.restore_local(name, previous);
+                }
+            }
+            WgslSegment::Export { name, expression } => {
+                let value =
+                    expression.evaluate_with_warnings(&context.state, &mut context.warnings)?;
+
+                // Not restored, the context is shared with the including
+                // shader which sees the value after the `//: include`
+                context.set_local(name, value);
+            }
+            WgslSegment::Default { name, expression } => {
+                if context.state().get(name).is_none() {
+                    let value =
+                        expression.evaluate_with_warnings(&context.state, &mut context.warnings)?;
+
+                    context.set_local(name, value);
+                }
+            }
+            WgslSegment::Binding(name) => {
+                let binding = match name {
+                    Some(name) => {
+                        let variable = format!("{}_BINDING", name.to_ascii_uppercase());
+                        let value = context
+                            .state()
+                            .get(&variable)
+                            .ok_or(WgslError::UndefinedVariable)?;
+
+                        binding_index(value)?
+                    }
+                    None => {
+                        let binding = context.next_binding;
+                        context.next_binding += 1;
+                        binding
+                    }
+                };
+
+                output.push_str(&format!("@group({}) @binding({binding})\n", context.group));
+            }
+            WgslSegment::Group(expression) => {
+                let value =
+                    expression.evaluate_with_warnings(&context.state, &mut context.warnings)?;
+
+                context.group = binding_index(value)?;
+                context.next_binding = 0;
+            }
+            WgslSegment::Pragma { key, value } => {
+                context.pragmas.insert(key.clone(), value.clone());
+            }
+            WgslSegment::Substitution { expression, suffix } => {
+                let value = expression.evaluate(context.state())?;
+
+                // Strings are written verbatim, without quotes, so
+                // `${USE_X ? "tangent" : "none"}` can choose a piece of text
+                match (suffix, value) {
+                    (Some(suffix), value) => output.push_str(&suffix.format(&value)?),
+                    (None, WgslLiteral::Str(text)) => output.push_str(&text),
+                    (None, value) => output.push_str(&to_wgsl(&value, None)?),
+                }
+            }
+            WgslSegment::Text(t) => output.push_str(t),
+        }
+
+        Ok(())
+    }
+
+    /// Parses `(line number, line)` pairs until the end of the input or an
+    /// `else`/`end` directive, which is returned as the end reason.
+    pub fn from_lines<'a>(
+        lines: &mut impl Iterator<Item = (usize, &'a str)>,
+    ) -> Result<(Option<Self>, WgslSegmentEndReason), WgslError> {
+        let mut segment = WgslSegment::Text(String::new());
+
+        while let Some((line_number, line)) = lines.next() {
+            let source = line;
+
+            let Some(line) = line.trim().strip_prefix("//:") else {
+                if line.contains("${") || line.contains("{{") {
+                    segment.concat(Self::from_text(line, line_number)?);
+                } else {
+                    segment.push_text(line);
+                }
+
+                segment.push_text("\n");
+                continue;
+            };
+
+            let line = line.trim_start();
+
+            let (operation, parameter) = line
+                .split_once(char::is_whitespace)
+                .map(|(operation, parameter)| (operation, parameter.trim_start()))
+                .unwrap_or((line, ""));
+
+            match operation {
+                "include" => {
+                    let (path, prefix) = match parameter.rsplit_once(" as ") {
+                        // A prefix never has path characters, so `a as b/c.wgsl`
+                        // is a single path
+                        Some((_, prefix)) if prefix.contains(['/', '\\', '.', '#']) => {
+                            (parameter, None)
+                        }
+                        Some((path, prefix)) => {
+                            let prefix = prefix.trim();
+                            validate_identifier(prefix).map_err(in_line(line_number, source))?;
+
+                            (path.trim_end(), Some(prefix.to_string()))
+                        }
+                        None => (parameter, None),
+                    };
+
+                    let (path, section) = match path.split_once('#') {
+                        Some((path, section)) => (path, Some(section.to_string())),
+                        None => (path, None),
+                    };
+
+                    segment.concat(WgslSegment::Include {
+                        path: path.into(),
+                        section,
+                        prefix,
+                    });
+                }
+                "guard" => Err(WgslError::MisplacedGuard { line: line_number })?,
+                "include_glob" => {
+                    if parameter.is_empty() {
+                        Err(WgslError::MissingParameter(operation.to_string()))?;
+                    }
+
+                    segment.concat(WgslSegment::IncludeGlob(parameter.into()));
+                }
+                "const" => {
+                    let (name, doc) = split_doc_comment(parameter);
+
+                    segment.concat(WgslSegment::Constant {
+                        name: name.to_string(),
+                        doc: doc.map(str::to_string),
+                    });
+                }
+                "const_expr" => {
+                    let (parameter, doc) = split_doc_comment(parameter);
+
+                    let (name, rest) = parameter.split_at(
+                        parameter
+                            .find(|ch: char| ch == ':' || ch.is_whitespace())
+                            .unwrap_or(parameter.len()),
+                    );
+
+                    if name.is_empty() {
+                        Err(WgslError::MissingParameter(operation.to_string()))?;
+                    }
+
+                    validate_identifier(name).map_err(in_line(line_number, source))?;
+
+                    let (ty, precision, expression) = match rest.trim_start().strip_prefix(':') {
+                        Some(rest) => {
+                            let (ty, expression) = split_type(rest.trim_start())?;
+
+                            if ty.is_empty() {
+                                Err(WgslError::InvalidType(rest.to_string()))?;
+                            }
+
+                            // `f32(6)`, the number of digits after the decimal point
+                            let (precision, expression) = match expression.strip_prefix('(') {
+                                Some(expression) => {
+                                    let (precision, expression) = expression
+                                        .split_once(')')
+                                        .ok_or(WgslError::InvalidType(rest.to_string()))?;
+                                    let precision = precision
+                                        .trim()
+                                        .parse()
+                                        .map_err(|_| WgslError::InvalidType(rest.to_string()))?;
+
+                                    (Some(precision), expression)
+                                }
+                                None => (None, expression),
+                            };
+
+                            (Some(ty.to_string()), precision, expression)
+                        }
+                        None => (None, None, rest),
+                    };
+
+                    segment.concat(WgslSegment::ConstantExpression {
+                        name: name.to_string(),
+                        ty,
+                        precision,
+                        expression: WgslExpression::new(expression)
+                            .map_err(in_line(line_number, source))?,
+                        doc: doc.map(str::to_string),
+                    });
+                }
+                "consts" => {
+                    let mut constants = Vec::new();
+
+                    loop {
+                        let Some((line, constant)) = lines.next() else {
+                            Err(WgslError::InvalidConstantsBlock { line: line_number })?
+                        };
+
+                        let constant = constant.trim();
+
+                        if let Some(directive) = constant.strip_prefix("//:") {
+                            match directive.split_whitespace().collect::<Vec<_>>()[..] {
+                                ["end"] | ["end", "consts"] => break,
+                                ["end", label] => Err(WgslError::MismatchedEnd {
+                                    expected: "consts".to_string(),
+                                    found: label.to_string(),
+                                    line,
+                                })?,
+                                _ => (),
+                            }
+
+                            Err(WgslError::InvalidConstant { line })?;
+                        }
+
+                        if constant.is_empty() || constant.starts_with("//") {
+                            continue;
+                        }
+
+                        let (name, expression) = constant
+                            .split_once('=')
+                            .map(|(name, expression)| (name.trim(), expression))
+                            .filter(|(name, _)| !name.is_empty())
+                            .ok_or(WgslError::InvalidConstant { line })?;
+
+                        let expression =
+                            WgslExpression::new(expression).map_err(in_line(line, constant))?;
+
+                        constants.push((name.to_string(), expression));
+                    }
+
+                    segment.concat(WgslSegment::Constants(constants));
+                }
+                "export" | "default" => {
+                    let (name, expression) = parameter
+                        .split_once(char::is_whitespace)
+                        .unwrap_or((parameter, ""));
+
+                    if name.is_empty() {
+                        Err(WgslError::MissingParameter(operation.to_string()))?;
+                    }
+
+                    validate_identifier(name).map_err(in_line(line_number, source))?;
+
+                    let name = name.to_string();
+                    let expression =
+                        WgslExpression::new(expression).map_err(in_line(line_number, source))?;
+
+                    segment.concat(match operation {
+                        "export" => WgslSegment::Export { name, expression },
+                        _ => WgslSegment::Default { name, expression },
+                    });
+                }
+                "binding" => {
+                    let name = parameter.trim_end();
+
+                    if name.is_empty() {
+                        Err(WgslError::MissingParameter(operation.to_string()))?;
+                    }
+
+                    segment.concat(WgslSegment::Binding(match name {
+                        "auto" => None,
+                        name => {
+                            validate_identifier(name).map_err(in_line(line_number, source))?;
+                            Some(name.to_string())
+                        }
+                    }));
+                }
+                "group" => {
+                    let expression =
+                        WgslExpression::new(parameter).map_err(in_line(line_number, source))?;
+
+                    segment.concat(WgslSegment::Group(expression));
+                }
+                "pragma" => {
+                    let (key, value) = parameter
+                        .split_once(char::is_whitespace)
+                        .unwrap_or((parameter, ""));
+
+                    if key.is_empty() {
+                        Err(WgslError::MissingParameter(operation.to_string()))?;
+                    }
+
+                    segment.concat(WgslSegment::Pragma {
+                        key: key.to_string(),
+                        value: value.trim().to_string(),
+                    });
+                }
+                "if" => {
+                    let condition =
+                        WgslExpression::new(parameter).map_err(in_line(line_number, source))?;
+
+                    // An if block that is still open at the end of the file
+                    // ends there, other errors point to the line of the `if`
+                    // itself
+                    let (if_true, if_false) = match WgslSegment::from_lines(lines)? {
+                        (Some(segment), WgslSegmentEndReason::ElseOp(_)) => {
+                            match WgslSegment::from_lines(lines)? {
+                                (
+                                    Some(if_false),
+                                    end @ (WgslSegmentEndReason::EndOp(..)
+                                    | WgslSegmentEndReason::EndOfFile),
+                                ) => {
+                                    end.check_end_label("if")?;
+                                    (Box::new(segment), Some(Box::new(if_false)))
+                                }
+                                (_, WgslSegmentEndReason::ElseOp(line)) => {
+                                    Err(WgslError::DuplicateElse { line })?
+                                }
+                                _ => Err(WgslError::InvalidIfBlock { line: line_number })?,
+                            }
+                        }
+                        (
+                            Some(segment),
+                            end @ (WgslSegmentEndReason::EndOp(..)
+                            | WgslSegmentEndReason::EndOfFile),
+                        ) => {
+                            end.check_end_label("if")?;
+                            (Box::new(segment), None)
+                        }
+                        _ => Err(WgslError::InvalidIfBlock { line: line_number })?,
+                    };
+
+                    segment.concat(WgslSegment::Conditional {
+                        condition,
+                        if_true,
+                        if_false,
+                    });
+                }
+                "for" => {
+                    let (variable, target) = parameter
+                        .split_once(" in ")
+                        .map(|(variable, target)| (variable.trim(), target))
+                        .ok_or(WgslError::InvalidLoop { line: line_number })?;
+
+                    validate_identifier(variable).map_err(in_line(line_number, source))?;
+
+                    let body = match WgslSegment::from_lines(lines)? {
+                        (Some(body), end @ WgslSegmentEndReason::EndOp(..)) => {
+                            end.check_end_label("for")?;
+                            body
+                        }
+                        (_, WgslSegmentEndReason::ElseOp(line)) => {
+                            Err(WgslError::UnexpectedElse { line })?
+                        }
+                        _ => Err(WgslError::InvalidLoop { line: line_number })?,
+                    };
+
+                    segment.concat(WgslSegment::Loop {
+                        variable: variable.to_string(),
+                        target: WgslLoopTarget::new(target)
+                            .map_err(in_line(line_number, source))?,
+                        body: Box::new(body),
+                    });
+                }
+                "section" => {
+                    if parameter.is_empty() {
+                        Err(WgslError::MissingParameter(operation.to_string()))?;
+                    }
+
+                    validate_identifier(parameter).map_err(in_line(line_number, source))?;
+
+                    let body = match WgslSegment::from_lines(lines)? {
+                        (Some(body), WgslSegmentEndReason::EndSectionOp(_)) => body,
+                        (_, WgslSegmentEndReason::ElseOp(line)) => {
+                            Err(WgslError::UnexpectedElse { line })?
+                        }
+                        (_, WgslSegmentEndReason::EndOp(line, _)) => {
+                            Err(WgslError::UnexpectedEnd { line })?
+                        }
+                        _ => Err(WgslError::InvalidSection { line: line_number })?,
+                    };
+
+                    segment.concat(WgslSegment::Section {
+                        name: parameter.to_string(),
+                        body: Box::new(body),
+                    });
+                }
+                "else" => return Ok((Some(segment), WgslSegmentEndReason::ElseOp(line_number))),
+                "end" => {
+                    let label = (!parameter.is_empty()).then(|| parameter.to_string());
+                    return Ok((
+                        Some(segment),
+                        WgslSegmentEndReason::EndOp(line_number, label),
+                    ));
+                }
+                "endsection" => {
+                    return Ok((
+                        Some(segment),
+                        WgslSegmentEndReason::EndSectionOp(line_number),
+                    ))
+                }
+                other => Err(in_line(line_number, source)(WgslError::UnknownOperation(
+                    other.to_string(),
+                )))?,
+            }
+        }
+
+        Ok((Some(segment), WgslSegmentEndReason::EndOfFile))
+    }
+
+    /// Splits `${expression}` substitutions and `{{#if}}` conditionals from a
+    /// line of text, `$${` is written as a literal `${` and `\{{#if ` as a
+    /// literal `{{#if `. Any other `$` or `{{` is kept as-is.
+    fn from_text(source: &str, line: usize) -> Result<Self, WgslError> {
+        let mut text = source;
+
+        match Self::from_inline(&mut text, source, line)? {
+            (segment, None) => Ok(segment),
+            (_, Some(INLINE_ELSE)) => Err(WgslError::UnexpectedElse { line }),
+            (_, Some(_)) => Err(WgslError::UnexpectedEnd { line }),
+        }
+    }
+
+    /// Parses `text` until its end or an `{{#else}}` or `{{/if}}`, which is
+    /// returned. Inline conditionals can be nested but have to end on the
+    /// line they start on.
+    fn from_inline(
+        text: &mut &str,
+        source: &str,
+        line: usize,
+    ) -> Result<(Self, Option<&'static str>), WgslError> {
+        let mut segment = WgslSegment::Text(String::new());
+
+        while let Some((i, tag)) = find_inline_tag(text) {
+            let escape = match tag {
+                "${" => '$',
+                _ => '\\',
+            };
+
+            if text[..i].ends_with(escape) {
+                segment.concat(WgslSegment::Text(format!("{}{tag}", &text[..i - 1])));
+                *text = &text[i + tag.len()..];
+                continue;
+            }
+
+            segment.concat(WgslSegment::Text(text[..i].to_string()));
+            *text = &text[i + tag.len()..];
+
+            match tag {
+                "${" => {
+                    let end = find_outside_strings(text, "}")
+                        .ok_or(WgslError::UnclosedSubstitution { line })?;
+
+                    // `${A ? B : C}` has a `:` too, a suffix is only split off
+                    // when the whole substitution is not an expression
+                    let (expression, suffix) = match WgslExpression::new(&text[..end]) {
+                        Ok(expression) => (expression, None),
+                        Err(error) => match text[..end].rsplit_once(':') {
+                            Some((expression, suffix)) => {
+                                let suffix = Some(WgslSuffix::new(suffix)?);
+                                let expression = WgslExpression::new(expression)
+                                    .map_err(in_line(line, source))?;
+
+                                (expression, suffix)
+                            }
+                            None => Err(in_line(line, source)(error))?,
+                        },
+                    };
+
+                    segment.concat(WgslSegment::Substitution { expression, suffix });
+                    *text = &text[end + 1..];
+                }
+                INLINE_IF => {
+                    let end = find_outside_strings(text, "}}")
+                        .ok_or(WgslError::InvalidIfBlock { line })?;
+                    let condition =
+                        WgslExpression::new(&text[..end]).map_err(in_line(line, source))?;
+                    *text = &text[end + 2..];
+
+                    let (if_true, if_false) = match Self::from_inline(text, source, line)? {
+                        (if_true, Some(INLINE_ELSE)) => {
+                            match Self::from_inline(text, source, line)? {
+                                (if_false, Some(INLINE_END)) => (if_true, Some(Box::new(if_false))),
+                                (_, Some(_)) => Err(WgslError::DuplicateElse { line })?,
+                                (_, None) => Err(WgslError::InvalidIfBlock { line })?,
+                            }
+                        }
+                        (if_true, Some(_)) => (if_true, None),
+                        (_, None) => Err(WgslError::InvalidIfBlock { line })?,
+                    };
+
+                    segment.concat(WgslSegment::InlineConditional {
+                        condition,
+                        if_true: Box::new(if_true),
+                        if_false,
+                    });
+                }
+                _ => return Ok((segment, Some(tag))),
+            }
+        }
+
+        segment.concat(WgslSegment::Text(text.to_string()));
+        *text = "";
+
+        Ok((segment, None))
+    }
+
+    /// Calls `visitor` with every variable used by this segment, includes are
+    /// not followed.
+    pub fn visit_references(&self, visitor: &mut dyn FnMut(&str)) {
+        self.visit_typed_references(&mut |name, _| visitor(name));
+    }
+
+    /// Same as [`WgslSegment::visit_references`] but also guesses the type of
+    /// every variable from where it is used.
+    pub fn visit_typed_references(&self, visitor: &mut dyn FnMut(&str, WgslInferredType)) {
+        match self {
+            WgslSegment::Conditional {
+                condition,
+                if_true,
+                if_false,
+            }
+            | WgslSegment::InlineConditional {
+                condition,
+                if_true,
+                if_false,
+            } => {
+                condition.visit_typed_references(WgslInferredType::Bool, visitor);
+                if_true.visit_typed_references(visitor);
+
+                if let Some(if_false) = if_false.as_ref() {
+                    if_false.visit_typed_references(visitor);
+                }
+            }
+            WgslSegment::Loop {
+                variable,
+                target,
+                body,
+            } => {
+                target.visit_typed_references(visitor);
+
+                // The loop variable is not a variable of the workspace
+                body.visit_typed_references(&mut |name, ty| {
+                    if name != variable {
+                        visitor(name, ty);
+                    }
+                });
+            }
+            WgslSegment::Sequence(sequence) => {
+                for segment in sequence.iter() {
+                    segment.visit_typed_references(visitor);
+                }
+            }
+            WgslSegment::Section { body, .. } => body.visit_typed_references(visitor),
+            WgslSegment::Constant { name, .. } => visitor(name, WgslInferredType::Unknown),
+            WgslSegment::ConstantExpression { expression, .. }
+            | WgslSegment::Export { expression, .. }
+            | WgslSegment::Default { expression, .. } => {
+                expression.visit_typed_references(WgslInferredType::Unknown, visitor)
+            }
+            WgslSegment::Binding(Some(name)) => visitor(
+                &format!("{}_BINDING", name.to_ascii_uppercase()),
+                WgslInferredType::Number,
+            ),
+            WgslSegment::Group(expression) => {
+                expression.visit_typed_references(WgslInferredType::Number, visitor)
+            }
+            WgslSegment::Constants(constants) => {
+                for (i, (_, expression)) in constants.iter().enumerate() {
+                    // Constants defined earlier in the block are not variables
+                    expression.visit_typed_references(
+                        WgslInferredType::Unknown,
+                        &mut |name, ty| {
+                            if !constants[..i].iter().any(|(defined, _)| defined == name) {
+                                visitor(name, ty);
+                            }
+                        },
+                    );
+                }
+            }
+            WgslSegment::Substitution { expression, suffix } => {
+                let expected = match suffix {
+                    Some(_) => WgslInferredType::Number,
+                    None => WgslInferredType::Unknown,
+                };
+
+                expression.visit_typed_references(expected, visitor)
+            }
+            WgslSegment::Include { .. }
+            | WgslSegment::IncludeGlob(_)
+            | WgslSegment::Binding(None)
+            | WgslSegment::Pragma { .. }
+            | WgslSegment::Text(_) => (),
+        }
+    }
+
+    /// Calls `visitor` with the condition of every `//: if` in this segment in
+    /// source order, includes are not followed.
+    pub fn visit_conditions(&self, visitor: &mut impl FnMut(&WgslExpression)) {
+        match self {
+            WgslSegment::Conditional {
+                condition,
+                if_true,
+                if_false,
+            }
+            | WgslSegment::InlineConditional {
+                condition,
+                if_true,
+                if_false,
+            } => {
+                visitor(condition);
+                if_true.visit_conditions(visitor);
+
+                if let Some(if_false) = if_false.as_ref() {
+                    if_false.visit_conditions(visitor);
+                }
+            }
+            WgslSegment::Loop { body, .. } | WgslSegment::Section { body, .. } => {
+                body.visit_conditions(visitor)
+            }
+            WgslSegment::Sequence(sequence) => {
+                for segment in sequence.iter() {
+                    segment.visit_conditions(visitor);
+                }
+            }
+            _ => (),
+        }
+    }
+
+    /// Calls `visitor` with every `//: include` and `//: include_glob` and
+    /// whether it is inside an `//: if` or `//: for`, which may leave it out.
+    /// Included shaders are not followed.
+    fn visit_includes(&self, conditional: bool, visitor: &mut dyn FnMut(&WgslSegment, bool)) {
+        match self {
+            WgslSegment::Include { .. } | WgslSegment::IncludeGlob(_) => visitor(self, conditional),
+            WgslSegment::Conditional {
+                if_true, if_false, ..
+            } => {
+                if_true.visit_includes(true, visitor);
+
+                if let Some(if_false) = if_false.as_ref() {
+                    if_false.visit_includes(true, visitor);
+                }
+            }
+            WgslSegment::Loop { body, .. } => body.visit_includes(true, visitor),
+            WgslSegment::Section { body, .. } => body.visit_includes(conditional, visitor),
+            WgslSegment::Sequence(sequence) => {
+                for segment in sequence.iter() {
+                    segment.visit_includes(conditional, visitor);
+                }
+            }
+            _ => (),
+        }
+    }
+
+    /// Adds the name of every `//: export` and `//: default` in this segment
+    /// to `names`, including the ones in branches that may not be taken.
+    fn collect_exports(&self, names: &mut HashSet<String>) {
+        match self {
+            WgslSegment::Export { name, .. } | WgslSegment::Default { name, .. } => {
+                _ = names.insert(name.clone())
+            }
+            WgslSegment::Conditional {
+                if_true, if_false, ..
+            } => {
+                if_true.collect_exports(names);
+
+                if let Some(if_false) = if_false.as_ref() {
+                    if_false.collect_exports(names);
+                }
+            }
+            WgslSegment::Loop { body, .. } | WgslSegment::Section { body, .. } => {
+                body.collect_exports(names)
+            }
+            WgslSegment::Sequence(sequence) => {
+                for segment in sequence.iter() {
+                    segment.collect_exports(names);
+                }
+            }
+            _ => (),
+        }
+    }
+
+    /// Same as [`WgslSegment::visit_references`] but stops at the first
+    /// reference to `variable`. Includes are followed only if the workspace
+    /// and the path of the shader this segment is in are given, shaders in
+    /// `visited` are not followed again.
+    fn depends_on(
+        &self,
+        variable: &str,
+        includes: Option<(&WgslWorkspace, &Path)>,
+        visited: &mut HashSet<PathBuf>,
+    ) -> Result<bool, WgslError> {
+        Ok(match self {
+            WgslSegment::Include { path, .. } => match includes {
+                Some((workspace, from)) => {
+                    let path = workspace.resolve_include(from, path)?;
+                    workspace.depends_on_visited(path, variable, visited)?
+                }
+                None => false,
+            },
+            WgslSegment::IncludeGlob(pattern) => match includes {
+                Some((workspace, from)) => {
+                    let mut depends = false;
+
+                    for path in workspace.resolve_include_glob(from, pattern) {
+                        depends =
+                            depends || workspace.depends_on_visited(path, variable, visited)?;
+                    }
+
+                    depends
+                }
+                None => false,
+            },
+            WgslSegment::Conditional {
+                condition,
+                if_true,
+                if_false,
+            }
+            | WgslSegment::InlineConditional {
+                condition,
+                if_true,
+                if_false,
+            } => {
+                condition.depends_on(variable)
+                    || if_true.depends_on(variable, includes, visited)?
+                    || match if_false.as_ref() {
+                        Some(if_false) => if_false.depends_on(variable, includes, visited)?,
+                        None => false,
+                    }
+            }
+            WgslSegment::Loop {
+                variable: loop_variable,
+                target,
+                body,
+            } => {
+                target.depends_on(variable)
+                    || (loop_variable != variable
+                        && body.depends_on(variable, includes, visited)?)
+            }
+            WgslSegment::Sequence(sequence) => {
+                for segment in sequence.iter() {
+                    if segment.depends_on(variable, includes, visited)? {
+                        return Ok(true);
+                    }
+                }
+
+                false
+            }
+            WgslSegment::Section { body, .. } => body.depends_on(variable, includes, visited)?,
+            WgslSegment::Constant { name, .. } => name == variable,
+            WgslSegment::ConstantExpression { expression, .. }
+            | WgslSegment::Export { expression, .. }
+            | WgslSegment::Default { expression, .. }
+            | WgslSegment::Group(expression) => expression.depends_on(variable),
+            WgslSegment::Binding(Some(name)) => {
+                format!("{}_BINDING", name.to_ascii_uppercase()) == variable
+            }
+            WgslSegment::Constants(constants) => {
+                for (name, expression) in constants.iter() {
+                    if expression.depends_on(variable) {
+                        return Ok(true);
+                    } else if name == variable {
+                        // Shadowed for the rest of the block
+                        break;
+                    }
+                }
+
+                false
+            }
+            WgslSegment::Substitution { expression, .. } => expression.depends_on(variable),
+            WgslSegment::Binding(None) | WgslSegment::Pragma { .. } | WgslSegment::Text(_) => false,
+        })
+    }
+
+    /// Writes the segment back as source code, directives are normalized and
+    /// text is kept as-is.
+    pub fn write_source(&self, output: &mut String) {
+        match self {
+            WgslSegment::Include {
+                path,
+                section,
+                prefix,
+            } => {
+                output.push_str(&format!("//: include {}", path.display()));
+
+                if let Some(section) = section {
+                    output.push_str(&format!("#{section}"));
+                }
+
+                if let Some(prefix) = prefix {
+                    output.push_str(&format!(" as {prefix}"));
+                }
+
+                output.push('\n');
+            }
+            WgslSegment::IncludeGlob(pattern) => {
+                output.push_str(&format!("//: include_glob {}\n", pattern.display()));
+            }
+            WgslSegment::Conditional {
+                condition,
+                if_true,
+                if_false,
+            } => {
+                output.push_str(&format!("//: if {}\n", condition.to_source()));
+                if_true.write_source(output);
+
+                if let Some(if_false) = if_false.as_ref() {
+                    output.push_str("//: else\n");
+                    if_false.write_source(output);
+                }
+
+                output.push_str("//: end\n");
+            }
+            WgslSegment::Loop {
+                variable,
+                target,
+                body,
+            } => {
+                let target = target.to_source();
+                output.push_str(&format!("//: for {variable} in {target}\n"));
+                body.write_source(output);
+                output.push_str("//: end\n");
+            }
+            WgslSegment::Sequence(sequence) => {
+                for segment in sequence.iter() {
+                    segment.write_source(output);
+                }
+            }
+            WgslSegment::Section { name, body } => {
+                output.push_str(&format!("//: section {name}\n"));
+                body.write_source(output);
+                output.push_str("//: endsection\n");
+            }
+            WgslSegment::Constant { name, doc } => {
+                output.push_str(&format!("//: const {name}"));
+                write_doc_source(output, doc.as_deref());
+            }
+            WgslSegment::ConstantExpression {
+                name,
+                ty: Some(ty),
+                precision,
+                expression,
+                doc,
+            } => {
+                let expression = expression.to_source();
+                let precision = precision.map(|p| format!("({p})")).unwrap_or_default();
+                output.push_str(&format!(
+                    "//: const_expr {name}: {ty}{precision} {expression}"
+                ));
+                write_doc_source(output, doc.as_deref());
+            }
+            WgslSegment::ConstantExpression {
+                name,
+                ty: None,
+                expression,
+                doc,
+                ..
+            } => {
+                let expression = expression.to_source();
+                output.push_str(&format!("//: const_expr {name} {expression}"));
+                write_doc_source(output, doc.as_deref());
+            }
+            WgslSegment::Constants(constants) => {
+                output.push_str("//: consts\n");
+
+                for (name, expression) in constants.iter() {
+                    output.push_str(&format!("{name} = {}\n", expression.to_source()));
+                }
+
+                output.push_str("//: end\n");
+            }
+            WgslSegment::Export { name, expression } => {
+                output.push_str(&format!("//: export {name} {}\n", expression.to_source()));
+            }
+            WgslSegment::Default { name, expression } => {
+                output.push_str(&format!("//: default {name} {}\n", expression.to_source()));
+            }
+            WgslSegment::Binding(name) => {
+                output.push_str(&format!(
+                    "//: binding {}\n",
+                    name.as_deref().unwrap_or("auto")
+                ));
+            }
+            WgslSegment::Group(expression) => {
+                output.push_str(&format!("//: group {}\n", expression.to_source()));
+            }
+            WgslSegment::Pragma { key, value } if value.is_empty() => {
+                output.push_str(&format!("//: pragma {key}\n"));
+            }
+            WgslSegment::Pragma { key, value } => {
+                output.push_str(&format!("//: pragma {key} {value}\n"));
+            }
+            WgslSegment::Substitution {
+                expression,
+                suffix: Some(suffix),
+            } => {
+                let (expression, suffix) = (expression.to_source(), suffix.symbol());
+                output.push_str(&format!("${{{expression}:{suffix}}}"));
+            }
+            WgslSegment::Substitution {
+                expression,
+                suffix: None,
+            } => {
+                output.push_str(&format!("${{{}}}", expression.to_source()));
+            }
+            WgslSegment::InlineConditional {
+                condition,
+                if_true,
+                if_false,
+            } => {
+                output.push_str(&format!("{{{{#if {}}}}}", condition.to_source()));
+                if_true.write_source(output);
+
+                if let Some(if_false) = if_false.as_ref() {
+                    output.push_str(INLINE_ELSE);
+                    if_false.write_source(output);
+                }
+
+                output.push_str(INLINE_END);
+            }
+            WgslSegment::Text(t) => {
+                let mut t = t.replace("${", "$${");
+
+                for tag in [INLINE_IF, INLINE_ELSE, INLINE_END] {
+                    t = t.replace(tag, &format!("\\{tag}"));
+                }
+
+                output.push_str(&t)
+            }
+        }
+    }
+
+    /// Walks every line of this segment, taking line numbers from `lines`,
+    /// in the order they are written. Lines inside a loop are walked once
+    /// per iteration, lines that are not `taken` are walked once.
+    fn walk_lines(
+        &self,
+        lines: &mut std::slice::Iter<(usize, bool)>,
+        taken: bool,
+        visitor: &mut dyn WgslLineVisitor,
+    ) {
+        match self {
+            WgslSegment::Include { .. } | WgslSegment::IncludeGlob(_) => {
+                walk_line(lines, WgslLineKind::Include, taken, visitor);
+            }
+            WgslSegment::Conditional {
+                condition,
+                if_true,
+                if_false,
+            } => {
+                let branch = taken.then(|| visitor.branch(condition)).flatten();
+                let (start, end) = match branch {
+                    Some(is_true) => (WgslLineKind::Condition(is_true), WgslLineKind::ConditionEnd),
+                    None => (WgslLineKind::Directive, WgslLineKind::Directive),
+                };
+
+                walk_line(lines, start, taken, visitor);
+                if_true.walk_lines(lines, taken && branch != Some(false), visitor);
+
+                if let Some(if_false) = if_false.as_ref() {
+                    let else_kind = match branch {
+                        Some(_) => WgslLineKind::ConditionEnd,
+                        None => WgslLineKind::Directive,
+                    };
+
+                    walk_line(lines, else_kind, taken, visitor);
+                    if_false.walk_lines(lines, taken && branch != Some(true), visitor);
+                }
+
+                walk_line(lines, end, taken, visitor);
+            }
+            WgslSegment::Loop { body, .. } => {
+                let iterations = match taken {
+                    true => visitor.iterations(),
+                    false => 0,
+                };
+
+                walk_line(lines, WgslLineKind::Loop(iterations), taken, visitor);
+
+                if iterations == 0 {
+                    body.walk_lines(lines, false, visitor);
+                } else {
+                    let start = lines.clone();
+
+                    for _ in 0..iterations {
+                        *lines = start.clone();
+                        body.walk_lines(lines, true, visitor);
+                    }
+                }
+
+                walk_line(lines, WgslLineKind::Directive, taken, visitor);
+            }
+            WgslSegment::Sequence(sequence) => {
+                for segment in sequence.iter() {
+                    segment.walk_lines(lines, taken, visitor);
+                }
+            }
+            WgslSegment::Section { body, .. } => {
+                walk_line(lines, WgslLineKind::Directive, taken, visitor);
+                body.walk_lines(lines, taken, visitor);
+                walk_line(lines, WgslLineKind::Directive, taken, visitor);
+            }
+            WgslSegment::Constants(_) => {
+                walk_line(lines, WgslLineKind::Directive, taken, visitor);
+
+                // Every line up to `//: end`, including comments
+                while lines
+                    .clone()
+                    .next()
+                    .is_some_and(|(_, directive)| !directive)
+                {
+                    walk_line(lines, WgslLineKind::Directive, taken, visitor);
+                }
+
+                walk_line(lines, WgslLineKind::Directive, taken, visitor);
+            }
+            // Inside a single line, they never consume lines
+            WgslSegment::InlineConditional { .. } | WgslSegment::Substitution { .. } => (),
+            WgslSegment::Text(text) => {
+                for _ in text.matches('\n') {
+                    walk_line(lines, WgslLineKind::Text, taken, visitor);
+                }
+            }
+            WgslSegment::Constant { .. }
+            | WgslSegment::ConstantExpression { .. }
+            | WgslSegment::Export { .. }
+            | WgslSegment::Default { .. }
+            | WgslSegment::Binding(_)
+            | WgslSegment::Group(_)
+            | WgslSegment::Pragma { .. } => {
+                walk_line(lines, WgslLineKind::Directive, taken, visitor);
+            }
+        }
+    }
+
+    /// Replaces every `//: if` whose condition doesn't use any variable or
+    /// function, like `//: if 0`, with the branch it always takes.
+    /// [`WgslFoldVisitor`] finds the lines that are left.
+    fn fold_conditions(self) -> WgslSegment {
+        match self {
+            WgslSegment::Conditional {
+                condition,
+                if_true,
+                if_false,
+            } => match condition.constant_fold().map(|value| value.is_truthy()) {
+                Some(Ok(true)) => if_true.fold_conditions(),
+                Some(Ok(false)) => match if_false {
+                    Some(if_false) => if_false.fold_conditions(),
+                    None => WgslSegment::Text(String::new()),
+                },
+                _ => WgslSegment::Conditional {
+                    condition,
+                    if_true: Box::new(if_true.fold_conditions()),
+                    if_false: if_false.map(|if_false| Box::new(if_false.fold_conditions())),
+                },
+            },
+            WgslSegment::InlineConditional {
+                condition,
+                if_true,
+                if_false,
+            } => match condition.constant_fold().map(|value| value.is_truthy()) {
+                Some(Ok(true)) => if_true.fold_conditions(),
+                Some(Ok(false)) => match if_false {
+                    Some(if_false) => if_false.fold_conditions(),
+                    None => WgslSegment::Text(String::new()),
+                },
+                _ => WgslSegment::InlineConditional {
+                    condition,
+                    if_true: Box::new(if_true.fold_conditions()),
+                    if_false: if_false.map(|if_false| Box::new(if_false.fold_conditions())),
+                },
+            },
+            WgslSegment::Loop {
+                variable,
+                target,
+                body,
+            } => WgslSegment::Loop {
+                variable,
+                target,
+                body: Box::new(body.fold_conditions()),
+            },
+            WgslSegment::Section { name, body } => WgslSegment::Section {
+                name,
+                body: Box::new(body.fold_conditions()),
+            },
+            WgslSegment::Sequence(sequence) => {
+                let mut folded = WgslSegment::Text(String::new());
+
+                for segment in sequence {
+                    // A folded `//: if` leaves its branch, which is merged in
+                    // so its text joins the text around it
+                    match segment.fold_conditions() {
+                        WgslSegment::Sequence(branch) => {
+                            for segment in branch {
+                                folded.concat(segment);
+                            }
+                        }
+                        segment => folded.concat(segment),
+                    }
+                }
+
+                folded
+            }
+            segment => segment,
+        }
+    }
+
+    /// Finds a `//: section`, sections inside `//: if` and `//: for` blocks
+    /// can't be found.
+    fn find_section(&self, name: &str) -> Option<&WgslSegment> {
+        match self {
+            WgslSegment::Section { name: n, body } if n == name => Some(body),
+            WgslSegment::Section { body, .. } => body.find_section(name),
+            WgslSegment::Sequence(sequence) => sequence
+                .iter()
+                .find_map(|segment| segment.find_section(name)),
+            _ => None,
+        }
+    }
+
+    /// Appends `text` to the text at the end of this segment, plain lines are
+    /// added this way so even a line of several megabytes is only copied
+    /// once.
+    fn push_text(&mut self, text: &str) {
+        match self {
+            WgslSegment::Text(left) => left.push_str(text),
+            WgslSegment::Sequence(sequence) => match sequence.last_mut() {
+                Some(WgslSegment::Text(left)) => left.push_str(text),
+                _ => sequence.push(WgslSegment::Text(text.to_string())),
+            },
+            _ => self.concat(WgslSegment::Text(text.to_string())),
+        }
+    }
+
+    #[inline]
+    pub fn can_concat_fast(&self, other: &WgslSegment) -> bool {
+        matches!(
+            (self, other),
+            (_, WgslSegment::Sequence(_))
+                | (WgslSegment::Sequence(_), _)
+                | (WgslSegment::Text(_), WgslSegment::Text(_))
+        )
+    }
+
+    pub fn concat(&mut self, other: WgslSegment) {
+        // Keeps parsed trees free of empty text
+        if matches!(self, WgslSegment::Text(text) if text.is_empty()) {
+            *self = other;
+            return;
+        }
+
+        match (self, other) {
+            (WgslSegment::Sequence(left), WgslSegment::Sequence(mut right)) => {
+                left.reserve(right.len());
+
+                for segment in right.drain(..) {
+                    if left.last().is_some_and(|l| l.can_concat_fast(&segment)) {
+                        unsafe { left.last_mut().unwrap_unchecked() }.concat(segment);
+                    } else {
+                        left.push(segment);
+                    }
+                }
+            }
+            (WgslSegment::Text(left), WgslSegment::Text(right)) => {
+                left.push_str(&right);
+            }
+            (left, mut right @ WgslSegment::Sequence(_)) => {
+                // The following is a safe version of:
+                // right.insert(0, left);
+                // left = right;
+
+                core::mem::swap(left, &mut right);
+
+                // Values now swapped so we swap names as well
+                let (left, right) = (right, left);
+
+                match right {
+                    WgslSegment::Sequence(sequence) => sequence.insert(0, left),
+                    _ => unreachable!(),
+                }
+            }
+            (WgslSegment::Sequence(ref mut sequence), right) => match sequence.last_mut() {
+                Some(segment) if segment.can_concat_fast(&right) => segment.concat(right),
+                _ => sequence.push(right),
+            },
+            (left, right) => {
+                // The following is basically a version of this but written in
+                // a way that the Rust compiler won't complain:
+                // *self = WgslSegment::Sequence(vec![left, right])
+
+                let mut sequence = WgslSegment::Sequence(Vec::new());
+                core::mem::swap(left, &mut sequence);
+
+                // Values now swapped so we swap names as well
+                let (sequence, left) = (left, sequence);
+
+                match sequence {
+                    WgslSegment::Sequence(ref mut sequence) => {
+                        sequence.push(left);
+                        sequence.push(right);
+                    }
+                    _ => unreachable!(),
+                }
+            }
+        }
+    }
+}
+
+/// Lines of a shader that is being parsed, calls `on_progress` with the line
+/// number every `every` lines and ends early when it returns `false`.
+struct WgslProgressLines<'a, I> {
+    lines: I,
+    every: usize,
+    count: usize,
+    on_progress: &'a mut dyn FnMut(usize) -> bool,
+    cancelled: bool,
+}
+
+impl<'a, I: Iterator<Item = (usize, &'a str)>> Iterator for WgslProgressLines<'_, I> {
+    type Item = (usize, &'a str);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.cancelled {
+            return None;
+        }
+
+        let (line, source) = self.lines.next()?;
+        self.count += 1;
+
+        if self.count.is_multiple_of(self.every) && !(self.on_progress)(line) {
+            self.cancelled = true;
+            return None;
+        }
+
+        Some((line, source))
+    }
+}
+
+#[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct WgslShader {
+    /// Set by a `//: guard` on the first line, the shader is empty when it is
+    /// false
+    guard: Option<WgslExpression>,
+    segment: WgslSegment,
+    capacity: usize,
+    /// Only kept when parsed with [`WgslShader::new_preserving`]
+    source: Option<String>,
+    /// The number of every parsed line and whether it is a directive, used
+    /// to map segments back to lines for [`WgslWorkspace::trace_shader`]
+    lines: Vec<(usize, bool)>,
+    /// Every directive as written, recorded before `//: if` blocks are
+    /// folded
+    directives: Vec<WgslDirective>,
+    /// See [`source_hash`]
+    hash: u64,
+}
+
+impl WgslShader {
+    /// Parses a shader, `//: if` blocks with a condition that doesn't use
+    /// any variable or function, like `//: if 0`, are replaced by the branch
+    /// they always take.
+    ///
+    /// Lines can be any length, parsing and writing take time linear in the
+    /// size of the shader even for a generated shader that is a single line
+    /// of several megabytes.
+    pub fn new(source: &str) -> Result<Self, WgslError> {
+        Self::parse(source, false, usize::MAX, &mut |_| true)
+    }
+
+    /// Same as [`WgslShader::new`] but calls `on_progress` with the current
+    /// line number every `every` lines, for very large shaders. Returning
+    /// `false` stops parsing with [`WgslError::Cancelled`], e.g. when an
+    /// editor has a newer version of the source to parse.
+    pub fn new_with_progress_cb(
+        source: &str,
+        every: usize,
+        mut on_progress: impl FnMut(usize) -> bool,
+    ) -> Result<Self, WgslError> {
+        Self::parse(source, false, every.max(1), &mut on_progress)
+    }
+
+    /// Parses the shader without removing indentation and empty lines or
+    /// folding `//: if` blocks, and keeps the text it was parsed from for
+    /// [`WgslShader::original_source`].
+    pub fn new_preserving(source: &str) -> Result<Self, WgslError> {
+        Self::parse(source, true, usize::MAX, &mut |_| true)
+    }
+
+    fn parse(
+        source: &str,
+        preserve: bool,
+        every: usize,
+        on_progress: &mut dyn FnMut(usize) -> bool,
+    ) -> Result<Self, WgslError> {
+        let capacity = source.len();
+        let mut lines = source
+            .lines()
+            .enumerate()
+            .map(|(i, line)| (i + 1, if preserve { line } else { line.trim() }))
+            .filter(|(_, line)| preserve || !line.is_empty());
+
+        let line_kinds: Vec<_> = lines
+            .clone()
+            .map(|(line, source)| (line, source.trim().starts_with("//:")))
+            .collect();
+
+        let directives = source
+            .lines()
+            .enumerate()
+            .filter_map(|(i, line)| WgslDirective::new(i + 1, line))
+            .collect();
+
+        let guard = match lines.clone().next() {
+            Some((line_number, line)) => match line.trim().strip_prefix("//:").map(str::trim_start)
+            {
+                Some(directive) if directive.split_whitespace().next() == Some("guard") => {
+                    lines.next();
+                    Some(
+                        WgslExpression::new(&directive["guard".len()..])
+                            .map_err(in_line(line_number, line))?,
+                    )
+                }
+                _ => None,
+            },
+            None => None,
+        };
+
+        let mut progress = WgslProgressLines {
+            lines: &mut lines,
+            every,
+            count: 0,
+            on_progress,
+            cancelled: false,
+        };
+
+        let segment = WgslSegment::from_lines(&mut progress);
+
+        // A cancelled parse ends in the middle of the shader, which would
+        // be reported as an unclosed block
+        if progress.cancelled {
+            Err(WgslError::Cancelled)?;
+        }
+
+        let segment = match segment? {
+            (_, WgslSegmentEndReason::ElseOp(line)) => Err(WgslError::UnexpectedElse { line })?,
+            (_, WgslSegmentEndReason::EndOp(line, _)) => Err(WgslError::UnexpectedEnd { line })?,
+            (_, WgslSegmentEndReason::EndSectionOp(line)) => {
+                Err(WgslError::UnexpectedEndSection { line })?
+            }
+            (segment, _) => segment.unwrap_or(WgslSegment::Text(String::new())),
+        };
+
+        if lines.clone().next().is_some() {
+            Err(WgslError::LeftoverChars(
+                lines.map(|(_, line)| line).collect(),
+            ))?;
+        }
+
+        // A preserved shader is kept as written, e.g. for formatting tools
+        let (segment, line_kinds) = match preserve {
+            true => (segment, line_kinds),
+            false => {
+                let mut line_kinds = line_kinds.iter();
+                let mut visitor = WgslFoldVisitor {
+                    kept: Vec::with_capacity(line_kinds.len()),
+                };
+
+                if guard.is_some() {
+                    visitor.kept.extend(line_kinds.next());
+                }
+
+                segment.walk_lines(&mut line_kinds, true, &mut visitor);
+
+                (segment.fold_conditions(), visitor.kept)
+            }
+        };
+
+        Ok(Self {
+            guard,
+            segment,
+            capacity,
+            source: preserve.then(|| source.to_string()),
+            lines: line_kinds,
+            directives,
+            hash: source_hash(source),
+        })
+    }
+
+    /// The parsed shader, e.g. to compare it against an expected tree.
+    pub fn segment(&self) -> &WgslSegment {
+        &self.segment
+    }
+
+    /// Serializes the parsed shader as JSON for tools that are not written in
+    /// Rust, `{"guard": <expression or null>, "segment": <segment>}`.
+    ///
+    /// Every enum is an object with the variant name as its only key, like
+    /// `{"Text": "..."}` or `{"Conditional": {"condition": ..., "if_true":
+    /// ..., "if_false": null}}`, and variants without fields are strings
+    /// like `"Add"`. Literals keep their type, `{"Integer": 1}` and `{"Float":
+    /// 1.0}` are different. A [`WgslSegment`] or [`WgslExpression`] can be
+    /// read back from its part of the JSON.
+    #[cfg(feature = "serde")]
+    pub fn to_ast_json(&self) -> Result<String, WgslError> {
+        #[derive(serde::Serialize)]
+        struct Ast<'a> {
+            guard: Option<&'a WgslExpression>,
+            segment: &'a WgslSegment,
+        }
+
+        serde_json::to_string(&Ast {
+            guard: self.guard.as_ref(),
+            segment: &self.segment,
+        })
+        .map_err(|error| WgslError::InvalidJson(error.to_string()))
+    }
+
+    /// Returns the text the shader was parsed from, unchanged, if it was
+    /// parsed with [`WgslShader::new_preserving`]. This is a copy of the
+    /// input, the tree doesn't know where its segments came from, see
+    /// [`WgslShader::format_source`] for source written from the tree.
+    pub fn original_source(&self) -> Option<&str> {
+        self.source.as_deref()
+    }
+
+    /// Returns every variable used in this shader, not including the ones
+    /// used by included shaders.
+    pub fn referenced_variables(&self) -> HashSet<String> {
+        let mut variables = HashSet::new();
+        let mut visitor = |variable: &str| _ = variables.insert(variable.to_string());
+
+        if let Some(guard) = self.guard.as_ref() {
+            guard.visit_references(&mut visitor);
+        }
+
+        self.segment.visit_references(&mut visitor);
+
+        variables
+    }
+
+    /// Returns every variable this shader needs from the workspace with the
+    /// type it probably has, in the order they are first used. Variables
+    /// that every workspace has, like `PI` and `BIT_3`, are left out, and so
+    /// are the variables of included shaders.
+    ///
+    /// The types are a best-effort guess from how each variable is used:
+    /// `//: if X` makes `X` a [`WgslInferredType::Bool`] but `X > 4` makes it
+    /// a [`WgslInferredType::Number`], a variable that is only written with
+    /// `//: const X` is [`WgslInferredType::Unknown`].
+    pub fn required_globals(&self) -> Vec<(String, WgslInferredType)> {
+        let builtins = WgslWorkspaceState::default();
+        let mut globals: Vec<(String, WgslInferredType)> = Vec::new();
+
+        let mut visitor = |variable: &str, ty: WgslInferredType| {
+            if builtins.get(variable).is_some() {
+                return;
+            }
+
+            match globals.iter_mut().find(|(name, _)| name == variable) {
+                Some((_, known)) => *known = known.merge(ty),
+                None => globals.push((variable.to_string(), ty)),
+            }
+        };
+
+        if let Some(guard) = self.guard.as_ref() {
+            guard.visit_typed_references(WgslInferredType::Bool, &mut visitor);
+        }
+
+        self.segment.visit_typed_references(&mut visitor);
+
+        globals
+    }
+
+    /// Returns every directive of this shader in source order, including the
+    /// ones in branches that may not be taken and in `//: if` blocks that
+    /// were already decided when the shader was parsed, without evaluating
+    /// anything. Parameters are kept exactly as written.
+    pub fn directives(&self) -> Vec<WgslDirective> {
+        self.directives.clone()
+    }
+
+    /// Returns the condition of every `//: if` in this shader in source
+    /// order, nested ones included, after the `//: guard` if there is one.
+    /// Together with [`WgslShader::referenced_variables`] this can be used to
+    /// render every variant of a shader.
+    pub fn branch_conditions(&self) -> Vec<WgslExpression> {
+        let mut conditions = self.guard.iter().cloned().collect::<Vec<_>>();
+
+        self.segment
+            .visit_conditions(&mut |condition| conditions.push(condition.clone()));
+
+        conditions
+    }
+
+    /// Returns `true` if `variable` is used anywhere in this shader, see
+    /// [`WgslWorkspace::depends_on`] to include included shaders.
+    pub fn depends_on(&self, variable: &str) -> bool {
+        self.guard
+            .as_ref()
+            .is_some_and(|guard| guard.depends_on(variable))
+            || matches!(
+                self.segment.depends_on(variable, None, &mut HashSet::new()),
+                Ok(true)
+            )
+    }
+
+    /// Returns `false` if the `//: guard` of this shader is false, in which
+    /// case the shader is empty.
+    pub fn guard_holds(&self, state: &WgslWorkspaceState) -> Result<bool, WgslError> {
+        match self.guard.as_ref() {
+            Some(guard) => guard.evaluate(state)?.is_truthy(),
+            None => Ok(true),
+        }
+    }
+
+    /// Reconstructs the source of this shader with all directives written in
+    /// their canonical form (`//: if x == 1` instead of `//:if   x==1`).
+    /// Formatting is idempotent. Indentation, empty lines and `//: if 0`
+    /// blocks are only kept for shaders parsed with
+    /// [`WgslShader::new_preserving`], see [`WgslShader::format`].
+    pub fn format_source(&self) -> String {
+        let mut result = String::with_capacity(self.capacity);
+
+        if let Some(guard) = self.guard.as_ref() {
+            result.push_str(&format!("//: guard {}\n", guard.to_source()));
+        }
+
+        self.segment.write_source(&mut result);
+
+        result
+    }
+
+    /// Formats `source` like [`WgslShader::format_source`] without losing
+    /// indentation, empty lines or blocks that are never written.
+    pub fn format(source: &str) -> Result<String, WgslError> {
+        Ok(Self::new_preserving(source)?.format_source())
+    }
+
+    /// Writes the whole shader, or only `section` if given, to the end of
+    /// `output`.
+    fn evaluate(
+        &self,
+        section: Option<&str>,
+        context: &mut WgslWriteContext,
+        output: &mut String,
+    ) -> Result<(), WgslError> {
+        let segment =
+            match section {
+                Some(section) => self.segment.find_section(section).ok_or_else(|| {
+                    WgslError::SectionNotFound {
+                        path: context.path.clone(),
+                        section: section.to_string(),
+                    }
+                })?,
+                None => &self.segment,
+            };
+
+        if !self.guard_holds(context.state())? {
+            return Ok(());
+        }
+
+        output.reserve(self.capacity);
+        segment.write_with(output, context)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct WgslWorkspaceState {
+    global_variables: HashMap<String, WgslLiteral>,
+    local_overrides: HashMap<String, WgslLiteral>,
+    functions: HashMap<String, WgslFunction>,
+    /// Tested with `feature("NAME")`
+    features: HashSet<String>,
+    int_overflow: IntOverflow,
+}
+
+impl WgslWorkspaceState {
+    pub fn get(&self, key: &str) -> Option<WgslLiteral> {
+        self.local_overrides
+            .get(key)
+            .or_else(|| self.global_variables.get(key))
+            .cloned()
+    }
+
+    pub fn function(&self, name: &str) -> Option<WgslFunction> {
+        self.functions.get(name).copied()
+    }
+
+    pub fn has_feature(&self, feature: &str) -> bool {
+        self.features.contains(feature)
+    }
+
+    pub fn int_overflow(&self) -> IntOverflow {
+        self.int_overflow
+    }
+
+    /// Parses and evaluates an expression in one step, e.g. `eval_expr("1 + 2")`.
+    pub fn eval_expr(&self, source: &str) -> Result<WgslLiteral, WgslError> {
+        WgslExpression::new(source)?.evaluate(self)
+    }
+}
+
+impl Default for WgslWorkspaceState {
+    fn default() -> Self {
+        let local_overrides = HashMap::new();
+        let mut global_variables = HashMap::new();
+
+        for i in 0..64 {
+            global_variables.insert(format!("BIT_{i}"), WgslLiteral::Integer(1 << i));
+        }
+
+        global_variables.insert("PI".into(), WgslLiteral::Float(core::f64::consts::PI));
+
+        let functions = BUILTIN_FUNCTIONS
+            .iter()
+            .map(|(name, function)| (name.to_string(), *function))
+            .collect();
+
+        Self {
+            global_variables,
+            local_overrides,
+            functions,
+            features: HashSet::new(),
+            int_overflow: IntOverflow::default(),
+        }
+    }
+}
+
+const DEFAULT_MAX_UNROLL: usize = 4096;
+const DEFAULT_MAX_VARIANTS: usize = 4096;
+
+/// A `Mutex` with the `sync` feature so a workspace can be shared between
+/// threads, a cheaper `RefCell` otherwise.
+#[cfg(feature = "sync")]
+type WgslCacheCell = std::sync::Mutex<WgslShaderCache>;
+#[cfg(not(feature = "sync"))]
+type WgslCacheCell = core::cell::RefCell<WgslShaderCache>;
+
+/// Rendered shaders by path, when more than `capacity` are stored the least
+/// recently used one is dropped.
+#[derive(Debug)]
+struct WgslShaderCache {
+    /// Every shader with the `clock` of when it was last used
+    entries: HashMap<PathBuf, (String, u64)>,
+    capacity: usize,
+    clock: u64,
+}
+
+impl WgslShaderCache {
+    fn new() -> Self {
+        Self {
+            entries: HashMap::new(),
+            capacity: usize::MAX,
+            clock: 0,
+        }
+    }
+
+    fn get(&mut self, path: &Path) -> Option<&str> {
+        self.clock += 1;
+
+        let (shader, last_used) = self.entries.get_mut(path)?;
+        *last_used = self.clock;
+
+        Some(shader)
+    }
+
+    fn insert(&mut self, path: PathBuf, shader: String) {
+        self.clock += 1;
+        self.entries.insert(path, (shader, self.clock));
+        self.evict();
+    }
+
+    fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = capacity;
+        self.evict();
+    }
+
+    fn evict(&mut self) {
+        while self.entries.len() > self.capacity {
+            let oldest = self
+                .entries
+                .iter()
+                .min_by_key(|(_, (_, last_used))| *last_used)
+                .map(|(path, _)| path.clone())
+                .unwrap();
+
+            self.entries.remove(&oldest);
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct WgslWorkspace {
+    state: WgslWorkspaceState,
+    root: PathBuf,
+    shaders: HashMap<PathBuf, WgslShader>,
+    max_unroll: usize,
+    max_variants: usize,
+    deny_undefined: bool,
+    /// Output of `get_shader`, cleared whenever the state changes. Only
+    /// locked to look up or store a shader, never while rendering, so
+    /// threads sharing a workspace render in parallel.
+    cache: WgslCacheCell,
+}
+
+impl WgslWorkspace {
+    pub fn scan(root: impl Into<PathBuf>) -> Self {
+        let shaders = HashMap::new();
+
+        Self::with_shaders(slash_path(root), shaders)
+    }
+
+    /// - `root`: The root of the workspace
+    /// - `shaders`: A list of shaders `(path, source)`, path is relative to
+    ///   `root`
+    pub fn from_memory(
+        root: impl Into<PathBuf>,
+        shaders: &[(&str, &str)],
+    ) -> Result<Self, WgslError> {
+        let shaders = shaders
+            .iter()
+            .map(|(path, source)| Ok((slash_path(*path), WgslShader::new(source)?)))
+            .collect::<Result<_, _>>()?;
+
+        Ok(Self::with_shaders(slash_path(root), shaders))
+    }
+
+    /// Moves the shaders of `other` into this workspace, keeping their paths
+    /// relative to the root of this workspace, so shaders of both can
+    /// include each other.
+    ///
+    /// - If both workspaces have a shader with the same path, nothing is
+    ///   moved and [`WgslError::DuplicateShader`] is returned
+    /// - Global variables, functions and features of `other` are only added
+    ///   when this workspace doesn't have them, this workspace always wins
+    /// - All other settings of `other` are dropped
+    pub fn merge(&mut self, other: WgslWorkspace) -> Result<(), WgslError> {
+        if let Some(path) = other
+            .shaders
+            .keys()
+            .find(|path| self.shaders.contains_key(*path))
+        {
+            return Err(WgslError::DuplicateShader(path.clone()));
+        }
+
+        self.clear_cache();
+        self.shaders.extend(other.shaders);
+
+        let state = other.state;
+
+        for (key, value) in state.global_variables {
+            self.state.global_variables.entry(key).or_insert(value);
+        }
+
+        for (name, function) in state.functions {
+            self.state.functions.entry(name).or_insert(function);
+        }
+
+        self.state.features.extend(state.features);
+
+        Ok(())
+    }
+
+    /// Returns `true` if this workspace has exactly the shaders of a
+    /// [`WgslWorkspace::from_memory`] call with `shaders`, with the same
+    /// sources. A workspace restored with `WgslWorkspace::from_cache_bytes`
+    /// can be checked with this before it is used.
+    pub fn is_up_to_date(&self, shaders: &[(&str, &str)]) -> bool {
+        shaders.len() == self.shaders.len()
+            && shaders.iter().all(|(path, source)| {
+                self.shaders
+                    .get(&slash_path(*path))
+                    .is_some_and(|shader| shader.hash == source_hash(source))
+            })
+    }
+
+    /// Saves the parsed shaders, global variables, features and settings so
+    /// they can be restored with [`WgslWorkspace::from_cache_bytes`] without
+    /// parsing every shader again. Registered functions can't be saved, they
+    /// have to be registered again after restoring.
+    #[cfg(feature = "serde")]
+    pub fn to_cache_bytes(&self) -> Result<Vec<u8>, WgslError> {
+        snapshot::encode(self)
+    }
+
+    /// Restores a workspace saved with [`WgslWorkspace::to_cache_bytes`].
+    /// Bytes that were saved by a different version of this crate are
+    /// [`WgslError::InvalidCache`], use [`WgslWorkspace::is_up_to_date`] to
+    /// find out if the shaders changed since they were saved.
+    #[cfg(feature = "serde")]
+    pub fn from_cache_bytes(bytes: &[u8]) -> Result<Self, WgslError> {
+        snapshot::decode(bytes)
+    }
+
+    fn with_shaders(root: PathBuf, shaders: HashMap<PathBuf, WgslShader>) -> Self {
+        Self {
+            state: WgslWorkspaceState::default(),
+            root,
+            shaders,
+            max_unroll: DEFAULT_MAX_UNROLL,
+            max_variants: DEFAULT_MAX_VARIANTS,
+            deny_undefined: false,
+            cache: WgslCacheCell::new(WgslShaderCache::new()),
+        }
+    }
+
+    pub fn set_global_i64(&mut self, key: &str, value: i64) {
+        self.clear_cache();
+        self.state
+            .global_variables
+            .insert(key.to_string(), WgslLiteral::Integer(value));
+    }
+
+    /// Stores the bit pattern of `value`, for flag masks where the highest
+    /// bit is set. Values above `i64::MAX` read as negative numbers in
+    /// expressions, test masks with `FLAGS & BIT_63 != 0` rather than `> 0`.
+    pub fn set_global_u64(&mut self, key: &str, value: u64) {
+        self.set_global_i64(key, value as i64);
+    }
+
+    /// Names a bit of a packed flags integer, `define_flag("USE_SHADOWS", 3)`
+    /// makes `//: if FLAGS & USE_SHADOWS` the same as `FLAGS & BIT_3`. Bits
+    /// above 63 are [`WgslError::InvalidShift`].
+    pub fn define_flag(&mut self, name: &str, bit: u32) -> Result<(), WgslError> {
+        let mask = 1i64
+            .checked_shl(bit)
+            .ok_or(WgslError::InvalidShift { amount: bit.into() })?;
+
+        self.set_global_i64(name, mask);
+
+        Ok(())
+    }
+
+    pub fn set_global_f64(&mut self, key: &str, value: f64) {
+        self.clear_cache();
+        self.state
+            .global_variables
+            .insert(key.to_string(), WgslLiteral::Float(value));
+    }
+
+    pub fn set_global_bool(&mut self, key: &str, value: bool) {
+        self.clear_cache();
+        self.state
+            .global_variables
+            .insert(key.to_string(), WgslLiteral::Bool(value));
+    }
+
+    /// Lists can be iterated with `//: for x in LIST` and written as WGSL
+    /// arrays.
+    pub fn set_global_list(&mut self, key: &str, value: Vec<WgslLiteral>) {
+        self.clear_cache();
+        self.state
+            .global_variables
+            .insert(key.to_string(), WgslLiteral::List(value));
+    }
+
+    /// Sets a global for every field of `value`, nested structs and maps are
+    /// flattened into dotted names: with the prefix `graphics`, the field
+    /// `quality.level` is read as `graphics.quality.level`. Numbers, bools,
+    /// strings, lists and enums without data are supported, anything else is
+    /// [`WgslError::UnsupportedValue`] and sets nothing.
+    ///
+    /// The globals of an earlier call with the same prefix are replaced, a
+    /// field that is now `None` or a map entry that was removed is no longer
+    /// set. Without a prefix only `None` fields are unset.
+    #[cfg(feature = "serde")]
+    pub fn set_globals_from<T: serde::Serialize + ?Sized>(
+        &mut self,
+        prefix: &str,
+        value: &T,
+    ) -> Result<(), WgslError> {
+        let globals = config::flatten(prefix, value)?;
+
+        self.clear_cache();
+
+        if !prefix.is_empty() {
+            self.state.global_variables.retain(|key, _| {
+                key.strip_prefix(prefix)
+                    .is_none_or(|rest| !rest.is_empty() && !rest.starts_with('.'))
+            });
+        }
+
+        for (key, value) in globals {
+            match value {
+                Some(value) => self.state.global_variables.insert(key, value),
+                None => self.state.global_variables.remove(&key),
+            };
+        }
+
+        Ok(())
+    }
+
+    /// Strings can be compared in expressions, e.g. `PROFILE == "release"`,
+    /// but can't be written to the shader.
+    pub fn set_global_str(&mut self, key: &str, value: &str) {
+        self.clear_cache();
+        self.state
+            .global_variables
+            .insert(key.to_string(), WgslLiteral::Str(value.to_string()));
+    }
+
+    /// Sets globals from a defines file, every line is `NAME = expression`
+    /// and can use the names defined above it. Empty lines and lines
+    /// starting with `#` are skipped:
+    ///
+    /// ```text
+    /// # Quality settings
+    /// SAMPLES = 16
+    /// HIGH_QUALITY = SAMPLES >= 16
+    /// ```
+    ///
+    /// Nothing is set if any line is invalid, the error has the line number
+    /// of that line.
+    pub fn load_defines(&mut self, source: &str) -> Result<(), WgslError> {
+        let mut state = self.state.clone();
+
+        for (i, line) in source.lines().enumerate() {
+            let (line_number, define) = (i + 1, line.trim());
+
+            if define.is_empty() || define.starts_with('#') {
+                continue;
+            }
+
+            let (name, expression) = define
+                .split_once('=')
+                .map(|(name, expression)| (name.trim(), expression))
+                .ok_or(WgslError::InvalidConstant { line: line_number })?;
+
+            validate_identifier(name).map_err(in_line(line_number, line))?;
+
+            let value = WgslExpression::new(expression)
+                .and_then(|expression| expression.evaluate(&state))
+                .map_err(in_line(line_number, line))?;
+
+            state.global_variables.insert(name.to_string(), value);
+        }
+
+        self.clear_cache();
+        self.state = state;
+
+        Ok(())
+    }
+
+    /// Replaces the enabled features, e.g. the GPU features a shader is
+    /// compiled for. Shaders test them with `feature("NAME")`.
+    pub fn set_features(&mut self, features: &[&str]) {
+        self.clear_cache();
+        self.state.features = features.iter().map(|feature| feature.to_string()).collect();
+    }
+
+    /// Makes `function` callable from expressions as `name(...)`, replacing
+    /// any built-in function with the same name.
+    pub fn register_function(&mut self, name: &str, function: WgslFunction) {
+        self.clear_cache();
+        self.state.functions.insert(name.to_string(), function);
+    }
+
+    /// Sets how integer overflow in expressions is handled, defaults to
+    /// [`IntOverflow::Checked`].
+    pub fn set_int_overflow(&mut self, int_overflow: IntOverflow) {
+        self.clear_cache();
+        self.state.int_overflow = int_overflow;
+    }
+
+    /// Sets the maximum number of iterations of a single `//: for` loop,
+    /// defaults to 4096.
+    pub fn set_max_unroll(&mut self, limit: usize) {
+        self.clear_cache();
+        self.max_unroll = limit;
+    }
+
+    /// Sets the maximum number of flag combinations
+    /// [`WgslWorkspace::distinct_variants`] renders, defaults to 4096 (12
+    /// flags).
+    pub fn set_max_variants(&mut self, limit: usize) {
+        self.max_variants = limit;
+    }
+
+    /// Makes [`WgslWorkspace::validate_all`] check that every variable used
+    /// by any shader is defined, even in branches that are not taken.
+    pub fn set_deny_undefined(&mut self, deny: bool) {
+        self.deny_undefined = deny;
+    }
+
+    /// Checks every shader in the workspace by rendering it with the current
+    /// global variables, returning the first error.
+    ///
+    /// With [`WgslWorkspace::set_deny_undefined`] every variable used in any
+    /// branch is checked first and all undefined ones are reported together
+    /// as [`WgslError::UndefinedVariables`]. Variables that only exist as a
+    /// loop variable of an including shader count as undefined, variables
+    /// set by an `//: export` or `//: default` in any shader count as
+    /// defined.
+    pub fn validate_all(&self) -> Result<(), WgslError> {
+        let mut paths = self.shaders.keys().collect::<Vec<_>>();
+        paths.sort();
+
+        if self.deny_undefined {
+            let mut undefined = Vec::new();
+
+            // Exported variables are defined by whichever shader includes
+            // their header, and variables with a `//: default` always have a
+            // value, they are not checked
+            let mut exports = HashSet::new();
+
+            for shader in self.shaders.values() {
+                shader.segment.collect_exports(&mut exports);
+            }
+
+            for path in paths.iter() {
+                let mut variables = self.shaders[*path]
+                    .referenced_variables()
+                    .into_iter()
+                    .filter(|variable| {
+                        self.state.get(variable).is_none() && !exports.contains(variable)
+                    })
+                    .collect::<Vec<_>>();
+
+                variables.sort();
+                undefined.extend(variables.into_iter().map(|v| ((*path).clone(), v)));
+            }
+
+            if !undefined.is_empty() {
+                Err(WgslError::UndefinedVariables(undefined))?;
+            }
+        }
+
+        for path in paths {
+            self.get_shader(path)?;
+        }
+
+        Ok(())
+    }
+
+    fn state(&self) -> &WgslWorkspaceState {
+        &self.state
+    }
+
+    /// Evaluates the shader at `path` with the current global variables.
+    ///
+    /// The output depends only on the loaded shaders and the global
+    /// variables, directives can not change global state. Calling this twice
+    /// without changing either always gives byte-identical output.
+    pub fn get_shader(&self, path: impl Into<PathBuf>) -> Result<String, WgslError> {
+        let path = slash_path(path);
+
+        let cached = self.cache().get(&path).map(str::to_string);
+
+        if let Some(shader) = cached {
+            return Ok(shader);
+        }
+
+        // The cache is never locked while rendering so rendering can safely
+        // call back into the workspace. Includes are not read from the cache
+        // because their output depends on the prefix and loop variables of
+        // the including shader, only the finished shader is stored.
+        let shader = self.render(path.clone(), None, &mut WgslWriteContext::new(self))?;
+        self.cache().insert(path, shader.clone());
+
+        Ok(shader)
+    }
+
+    /// Same as [`WgslWorkspace::get_shader`] but writes the shader to
+    /// `output`, which is cleared first. Reusing the same `String` for many
+    /// shaders avoids allocating a new one every time, only the first render
+    /// of a shader allocates to fill the cache.
+    pub fn get_shader_into(
+        &self,
+        path: impl Into<PathBuf>,
+        output: &mut String,
+    ) -> Result<(), WgslError> {
+        let path = slash_path(path);
+        output.clear();
+
+        if let Some(shader) = self.cache().get(&path) {
+            output.push_str(shader);
+            return Ok(());
+        }
+
+        let result = self.render_into(path.clone(), None, &mut WgslWriteContext::new(self), output);
+
+        if result.is_err() {
+            output.clear();
+        }
+
+        result?;
+        self.cache().insert(path, output.clone());
+
+        Ok(())
+    }
+
+    /// Same as [`WgslWorkspace::get_shader`] but returns `None` instead of an
+    /// empty shader when the `//: guard` of the shader is false.
+    pub fn try_get_shader(&self, path: impl Into<PathBuf>) -> Result<Option<String>, WgslError> {
+        let path = slash_path(path);
+        let shader = self.shaders.get(&path).ok_or(WgslError::NotFound)?;
+
+        if shader.guard_holds(self.state())? {
+            self.get_shader(path).map(Some)
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Same as [`WgslWorkspace::get_shader`] but calls `on_constant` with the
+    /// name and value of every constant in the order they are written,
+    /// constants in branches that are not taken are skipped. The cache is not
+    /// used.
+    pub fn get_shader_with_constants_cb(
+        &self,
+        path: impl Into<PathBuf>,
+        mut on_constant: impl FnMut(&str, WgslLiteral),
+    ) -> Result<String, WgslError> {
+        let mut context = WgslWriteContext::new(self);
+        context.on_constant = Some(&mut on_constant);
+
+        self.render(path, None, &mut context)
+    }
+
+    /// A panic while the cache is locked can only leave a shader out of it,
+    /// so a poisoned cache is still used.
+    #[cfg(feature = "sync")]
+    fn cache(&self) -> std::sync::MutexGuard<'_, WgslShaderCache> {
+        self.cache
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner)
+    }
+
+    #[cfg(not(feature = "sync"))]
+    fn cache(&self) -> core::cell::RefMut<'_, WgslShaderCache> {
+        self.cache.borrow_mut()
+    }
+
+    #[cfg(feature = "sync")]
+    fn cache_mut(&mut self) -> &mut WgslShaderCache {
+        self.cache
+            .get_mut()
+            .unwrap_or_else(std::sync::PoisonError::into_inner)
+    }
+
+    #[cfg(not(feature = "sync"))]
+    fn cache_mut(&mut self) -> &mut WgslShaderCache {
+        self.cache.get_mut()
+    }
+
+    /// Forgets all cached shaders, changing any variable or setting already
+    /// does this automatically.
+    pub fn clear_cache(&mut self) {
+        self.cache_mut().entries.clear();
+    }
+
+    /// Forgets the cached output of the shader at `path`, shaders including it
+    /// are not affected.
+    pub fn clear_cache_for(&mut self, path: impl Into<PathBuf>) {
+        self.cache_mut().entries.remove(&slash_path(path));
+    }
+
+    /// Limits how many rendered shaders are cached, when a shader is rendered
+    /// while the cache is full the least recently used one is forgotten.
+    /// There is no limit by default, `0` turns the cache off.
+    pub fn set_cache_capacity(&mut self, capacity: usize) {
+        self.cache_mut().set_capacity(capacity);
+    }
+
+    /// Returns the `//: pragma <key> <value>` directives that are reached when
+    /// evaluating the shader, pragmas in branches that are not taken are not
+    /// included.
+    pub fn pragmas(&self, path: impl Into<PathBuf>) -> Result<HashMap<String, String>, WgslError> {
+        let mut context = WgslWriteContext::new(self);
+        self.render(path, None, &mut context)?;
+
+        Ok(context.pragmas)
+    }
+
+    /// Evaluates an expression with the current global variables, e.g. one
+    /// built with [`WgslExpression::and`] and friends.
+    pub fn evaluate(&self, expression: &WgslExpression) -> Result<WgslLiteral, WgslError> {
+        expression.evaluate(self.state())
+    }
+
+    /// Renders the shader with `flag` set to `true` and to `false` and
+    /// returns whether the outputs differ, useful for finding flags that
+    /// don't do anything. The global value of `flag` is not changed.
+    pub fn flag_affects_output(
+        &self,
+        path: impl Into<PathBuf>,
+        flag: &str,
+    ) -> Result<bool, WgslError> {
+        let path = slash_path(path);
+
+        let render_with = |value| {
+            let mut context = WgslWriteContext::new(self);
+            context.set_local(flag, WgslLiteral::Bool(value));
+
+            self.render(path.clone(), None, &mut context)
+        };
+
+        Ok(render_with(true)? != render_with(false)?)
+    }
+
+    /// Renders the shader at `path` with every combination of `true` and
+    /// `false` for `flags` and returns the distinct outputs, in the order
+    /// they are first rendered (all flags `false` first). Combinations that
+    /// render the same shader are only returned once. The global values of
+    /// `flags` are not changed.
+    ///
+    /// Rendering more combinations than the limit set with
+    /// [`WgslWorkspace::set_max_variants`] is [`WgslError::TooManyVariants`].
+    pub fn distinct_variants(
+        &self,
+        path: impl Into<PathBuf>,
+        flags: &[&str],
+    ) -> Result<Vec<String>, WgslError> {
+        let path = slash_path(path);
+
+        let count = u32::try_from(flags.len())
+            .ok()
+            .and_then(|len| 1usize.checked_shl(len))
+            .unwrap_or(usize::MAX);
+
+        if count > self.max_variants {
+            Err(WgslError::TooManyVariants {
+                count,
+                limit: self.max_variants,
+            })?;
+        }
+
+        let mut seen = HashSet::new();
+        let mut variants = Vec::new();
+
+        for combination in 0..count {
+            let mut context = WgslWriteContext::new(self);
+
+            for (i, flag) in flags.iter().enumerate() {
+                context.set_local(flag, WgslLiteral::Bool(combination & (1 << i) != 0));
+            }
+
+            let variant = self.render(path.clone(), None, &mut context)?;
+
+            if seen.insert(variant.clone()) {
+                variants.push(variant);
+            }
+        }
+
+        Ok(variants)
+    }
+
+    /// Returns what happens to every line of the shader at `path` with the
+    /// current global variables: written, skipped, included, or the result of
+    /// a directive. Lines in loops appear once per iteration and included
+    /// shaders are not traced.
+    pub fn trace_shader(&self, path: impl Into<PathBuf>) -> Result<Vec<WgslLineTrace>, WgslError> {
+        let path = slash_path(path);
+        let shader = self.shaders.get(&path).ok_or(WgslError::NotFound)?;
+
+        let mut context = WgslWriteContext::new(self);
+        context.decisions = Some(Vec::new());
+        self.render_into(path, None, &mut context, &mut String::new())?;
+
+        let mut visitor = WgslTraceVisitor {
+            decisions: context.decisions.unwrap_or_default().into_iter(),
+            trace: Vec::new(),
+        };
+
+        let mut lines = shader.lines.iter();
+        let mut taken = true;
+
+        if shader.guard.is_some() {
+            taken = shader.guard_holds(self.state())?;
+            walk_line(
+                &mut lines,
+                WgslLineKind::Condition(taken),
+                true,
+                &mut visitor,
+            );
+        }
+
+        shader.segment.walk_lines(&mut lines, taken, &mut visitor);
+
+        Ok(visitor.trace)
+    }
+
+    /// Returns the warnings found while evaluating the shader, only constants
+    /// are checked.
+    pub fn warnings(&self, path: impl Into<PathBuf>) -> Result<Vec<WgslWarning>, WgslError> {
+        let mut context = WgslWriteContext::new(self);
+        self.render(path, None, &mut context)?;
+
+        Ok(context.warnings)
+    }
+
+    /// Returns `true` if `variable` is used by the shader at `path` or any of
+    /// the shaders it includes.
+    pub fn depends_on(&self, path: impl Into<PathBuf>, variable: &str) -> Result<bool, WgslError> {
+        self.depends_on_visited(slash_path(path), variable, &mut HashSet::new())
+    }
+
+    /// Same as [`WgslWorkspace::depends_on`], shaders that are already in
+    /// `visited` are skipped so include cycles end.
+    fn depends_on_visited(
+        &self,
+        path: PathBuf,
+        variable: &str,
+        visited: &mut HashSet<PathBuf>,
+    ) -> Result<bool, WgslError> {
+        let shader = self.shaders.get(&path).ok_or(WgslError::NotFound)?;
+
+        if !visited.insert(path.clone()) {
+            return Ok(false);
+        }
+
+        Ok(shader
+            .guard
+            .as_ref()
+            .is_some_and(|guard| guard.depends_on(variable))
+            || shader
+                .segment
+                .depends_on(variable, Some((self, &path)), visited)?)
+    }
+
+    /// Returns the globals the shader at `other` needs that the shader at
+    /// `base` doesn't, e.g. the flags a variant adds to a shared shader.
+    /// Both shaders include the globals of the shaders they include, and
+    /// variables set by an `//: export` or `//: default` are left out.
+    pub fn extra_globals(
+        &self,
+        base: impl Into<PathBuf>,
+        other: impl Into<PathBuf>,
+    ) -> Result<HashSet<String>, WgslError> {
+        let base = self.required_global_names(slash_path(base))?;
+        let other = self.required_global_names(slash_path(other))?;
+
+        Ok(other.difference(&base).cloned().collect())
+    }
+
+    /// The names of [`WgslShader::required_globals`] for the shader at `path`
+    /// and every shader it includes.
+    fn required_global_names(&self, path: PathBuf) -> Result<HashSet<String>, WgslError> {
+        let mut paths = vec![path.clone()];
+        paths.extend(
+            self.dependencies_detailed(path)?
+                .into_iter()
+                .map(|(path, _)| path),
+        );
+
+        let mut globals = HashSet::new();
+        let mut exports = HashSet::new();
+
+        for path in paths.iter() {
+            let shader = self.shaders.get(path).ok_or(WgslError::NotFound)?;
+
+            globals.extend(shader.required_globals().into_iter().map(|(name, _)| name));
+            shader.segment.collect_exports(&mut exports);
+        }
+
+        globals.retain(|name| !exports.contains(name));
+
+        Ok(globals)
+    }
+
+    /// Returns every shader included by the shader at `path`, directly or
+    /// through other includes, in the order they are first found. The `bool`
+    /// is `true` if the shader is always included and `false` if every way
+    /// it is included is behind an `//: if` or inside a `//: for`.
+    pub fn dependencies_detailed(
+        &self,
+        path: impl Into<PathBuf>,
+    ) -> Result<Vec<(PathBuf, bool)>, WgslError> {
+        let mut dependencies: Vec<(PathBuf, bool)> = Vec::new();
+        let mut pending = vec![(slash_path(path), true)];
+
+        while let Some((from, always)) = pending.pop() {
+            let shader = self.shaders.get(&from).ok_or(WgslError::NotFound)?;
+            // Everything a guarded shader includes may be left out
+            let always = always && shader.guard.is_none();
+            let mut includes = Vec::new();
+
+            shader
+                .segment
+                .visit_includes(false, &mut |include, conditional| {
+                    includes.push((include.clone(), always && !conditional))
+                });
+
+            let mut resolved = Vec::new();
+
+            for (include, always) in includes {
+                match include {
+                    WgslSegment::IncludeGlob(pattern) => resolved.extend(
+                        self.resolve_include_glob(&from, pattern)
+                            .into_iter()
+                            .map(|include| (include, always)),
+                    ),
+                    WgslSegment::Include { path, .. } => {
+                        resolved.push((self.resolve_include(&from, path)?, always))
+                    }
+                    _ => unreachable!(),
+                }
+            }
+
+            let mut follow = Vec::new();
+
+            for (include, always) in resolved {
+                match dependencies.iter_mut().find(|(path, _)| *path == include) {
+                    // Only revisited when it turns out to be always included,
+                    // which also stops include cycles
+                    Some((_, known)) if *known || !always => continue,
+                    Some((_, known)) => *known = true,
+                    None => dependencies.push((include.clone(), always)),
+                }
+
+                follow.push((include, always));
+            }
+
+            // Reversed so includes are followed in source order
+            pending.extend(follow.into_iter().rev());
+        }
+
+        Ok(dependencies)
+    }
+
+    /// Finds the shader an `//: include` in the shader at `from` refers to:
+    ///
+    /// - Paths starting with `./` or `../` are relative to the directory of
+    ///   `from`
+    /// - Absolute paths inside the workspace root are made relative to it
+    /// - Any other path is relative to the workspace root
+    pub fn resolve_include(
+        &self,
+        from: &Path,
+        include: impl AsRef<Path>,
+    ) -> Result<PathBuf, WgslError> {
+        let path = self.include_path(from, include.as_ref());
+
+        if self.shaders.contains_key(&path) {
+            Ok(path)
+        } else {
+            Err(WgslError::NotFound)
+        }
+    }
+
+    /// Finds the shaders an `//: include_glob` in the shader at `from` refers
+    /// to, sorted by path. The pattern is resolved like in
+    /// [`WgslWorkspace::resolve_include`], `*` and `?` match within a single
+    /// directory. The shader at `from` never matches itself and no matches
+    /// is not an error.
+    pub fn resolve_include_glob(&self, from: &Path, pattern: impl AsRef<Path>) -> Vec<PathBuf> {
+        let pattern = self.include_path(from, pattern.as_ref());
+
+        let mut matches: Vec<PathBuf> = self
+            .shaders
+            .keys()
+            .filter(|path| {
+                *path != from && path.components().count() == pattern.components().count()
+            })
+            .filter(|path| {
+                path.components()
+                    .zip(pattern.components())
+                    .all(|(name, pattern)| {
+                        glob_matches(
+                            &pattern.as_os_str().to_string_lossy(),
+                            &name.as_os_str().to_string_lossy(),
+                        )
+                    })
+            })
+            .cloned()
+            .collect();
+
+        matches.sort();
+        matches
+    }
+
+    fn include_path(&self, from: &Path, include: &Path) -> PathBuf {
+        let include = slash_path(include);
+
+        match include.components().next() {
+            Some(Component::CurDir | Component::ParentDir) => {
+                normalize_path(&from.parent().unwrap_or(Path::new("")).join(include))
+            }
+            _ => match include.strip_prefix(&self.root) {
+                Ok(relative) if is_absolute_path(&include) => normalize_path(relative),
+                _ => normalize_path(&include),
+            },
+        }
+    }
+
+    fn render(
+        &self,
+        path: impl Into<PathBuf>,
+        section: Option<&str>,
+        context: &mut WgslWriteContext,
+    ) -> Result<String, WgslError> {
+        let mut output = String::new();
+        self.render_into(path, section, context, &mut output)?;
+
+        Ok(output)
+    }
+
+    fn render_into(
+        &self,
+        path: impl Into<PathBuf>,
+        section: Option<&str>,
+        context: &mut WgslWriteContext,
+        output: &mut String,
+    ) -> Result<(), WgslError> {
+        let path = slash_path(path);
+        let shader = self.shaders.get(&path).ok_or(WgslError::NotFound)?;
+
+        let parent = core::mem::replace(&mut context.path, path);
+        let result = shader.evaluate(section, context, output);
+        context.path = parent;
+
+        result
+    }
+}
+
+/// A directive of a shader, see [`WgslShader::directives`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct WgslDirective {
+    pub line: usize,
+    /// The operation, e.g. `if` or `include`
+    pub kind: String,
+    /// Everything after the operation as written, `x==1 // note` for
+    /// `//: if x==1 // note`
+    pub parameter: String,
+}
+
+impl WgslDirective {
+    /// Returns `None` if `source` is not a directive line.
+    fn new(line: usize, source: &str) -> Option<Self> {
+        let directive = source.trim().strip_prefix("//:")?.trim_start();
+        let (kind, parameter) = directive
+            .split_once(char::is_whitespace)
+            .unwrap_or((directive, ""));
+
+        Some(Self {
+            line,
+            kind: kind.to_string(),
+            parameter: parameter.trim().to_string(),
+        })
+    }
+}
+
+/// What happened to a line of a shader, see [`WgslWorkspace::trace_shader`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct WgslLineTrace {
+    pub line: usize,
+    pub action: WgslLineAction,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum WgslLineAction {
+    /// Written to the output
+    Text,
+    /// A directive that doesn't decide anything, like `//: const` or `//: end`
+    Directive,
+    /// An `//: if` and whether its first branch is taken
+    Condition(bool),
+    /// A `//: for` and how many times its body is written
+    Loop(usize),
+    /// An `//: include` and the resolved path of the included shader
+    Included(PathBuf),
+    /// An `//: include_glob` and the paths of every matched shader
+    IncludedGlob(Vec<PathBuf>),
+    /// Inside a branch that is not taken
+    Skipped,
+}
+
+/// Something that is valid but probably not what was intended, see
+/// [`WgslWorkspace::warnings`].
+#[derive(Debug, Clone, PartialEq)]
+pub enum WgslWarning {
+    /// Integer division with a remainder, e.g. `5 / 2` is `2`. Write `5.0 /
+    /// 2.0` for a float result.
+    LossyDivision { left: i64, right: i64 },
+}
+
+#[derive(Debug, Clone)]
+pub enum WgslError {
+    UnknownOperation(String),
+    /// An error in a line of a shader, `source` is the line as it was
+    /// written, e.g. `//: fi USE_X` for an unknown operation
+    InLine {
+        line: usize,
+        source: String,
+        error: Box<WgslError>,
+    },
+    MissingParameter(String),
+    InvalidIfBlock {
+        line: usize,
+    },
+    InvalidLoop {
+        line: usize,
+    },
+    /// A `//: consts` block without `//: end`
+    InvalidConstantsBlock {
+        line: usize,
+    },
+    /// A line in a `//: consts` block or in a file given to
+    /// [`WgslWorkspace::load_defines`] that isn't `NAME = expression`
+    InvalidConstant {
+        line: usize,
+    },
+    LoopTooLarge {
+        count: usize,
+        limit: usize,
+    },
+    /// [`WgslWorkspace::distinct_variants`] would render more flag
+    /// combinations than [`WgslWorkspace::set_max_variants`] allows
+    TooManyVariants {
+        count: usize,
+        limit: usize,
+    },
+    /// A `//: for` loop over something that is not a list or a range
+    NotIterable(WgslLiteral),
+    DuplicateElse {
+        line: usize,
+    },
+    /// A labeled end like `//: end for` that closes a different kind of
+    /// block
+    MismatchedEnd {
+        expected: String,
+        found: String,
+        line: usize,
+    },
+    UnexpectedElse {
+        line: usize,
+    },
+    UnexpectedEnd {
+        line: usize,
+    },
+    /// A `//: section` without `//: endsection`
+    InvalidSection {
+        line: usize,
+    },
+    UnexpectedEndSection {
+        line: usize,
+    },
+    /// A `//: guard` that is not on the first line of the shader
+    MisplacedGuard {
+        line: usize,
+    },
+    /// An `//: include path#section` for a section that doesn't exist
+    SectionNotFound {
+        path: PathBuf,
+        section: String,
+    },
+    NoExpression,
+    NoClosingParenthesis,
+    NoClosingBracket,
+    /// A `condition ? a` without `: b`
+    NoColon,
+    DuplicatePeriod,
+    InvalidBase,
+    ParseFloatError(ParseFloatError),
+    ParseIntError(ParseIntError),
+    LeftoverChars(String),
+    /// Two expressions next to each other without an operator between them,
+    /// e.g. `B` in `A B` where `A && B` was meant
+    UnexpectedToken(String),
+    UnclosedString,
+    /// An expression could not be parsed, `offset` is the byte offset in the
+    /// expression where parsing stopped and `snippet` points to it.
+    InvalidSyntax {
+        offset: usize,
+        snippet: String,
+        error: Box<WgslError>,
+    },
+    UnclosedSubstitution {
+        line: usize,
+    },
+    /// The part after `:` in `${expression:suffix}` is not `f`, `i` or `u`
+    InvalidSuffix(String),
+    /// The value of a substitution can't be written with its suffix, e.g. a
+    /// float with `:u`
+    SuffixMismatch {
+        suffix: WgslSuffix,
+        value: WgslLiteral,
+    },
+    UndefinedVariable,
+    /// Every `(shader, variable)` that is used but not defined, see
+    /// [`WgslWorkspace::validate_all`]
+    UndefinedVariables(Vec<(PathBuf, String)>),
+    InvalidIdentifier(String),
+    InvalidType(String),
+    InvalidExpression,
+    /// A `//: group` or `NAME_BINDING` that is not an integer in the range
+    /// of a `u32`
+    InvalidBinding(WgslLiteral),
+    /// A condition evaluated to NaN, which is neither true nor false
+    NanCondition,
+    /// A string was used in a constant, or in a list that is written to the
+    /// shader
+    EmittedString,
+    /// A NaN or infinite float would be written to the shader, WGSL has no
+    /// literal for it
+    NonFiniteFloat(WgslLiteral),
+    /// A float that is not zero would be written as zero with the precision
+    /// of its `//: const_expr`, e.g. `0.000001` as `f32(2)`
+    RoundedToZero {
+        value: WgslLiteral,
+        precision: usize,
+    },
+    ArithmeticOverflow,
+    DivisionByZero,
+    /// A shift by a negative amount or by 64 or more bits
+    InvalidShift {
+        amount: i64,
+    },
+    UnknownFunction(String),
+    InvalidArgumentCount {
+        expected: usize,
+        found: usize,
+    },
+    NotFound,
+    /// The callback of [`WgslShader::new_with_progress_cb`] stopped parsing
+    Cancelled,
+    /// A value given to `WgslWorkspace::set_globals_from` that has no
+    /// matching literal, e.g. an enum with data
+    UnsupportedValue(String),
+    /// The error of `serde_json` when `WgslShader::to_ast_json` fails
+    InvalidJson(String),
+    /// Both workspaces given to [`WgslWorkspace::merge`] have a shader at
+    /// this path
+    DuplicateShader(PathBuf),
+    /// Bytes given to `WgslWorkspace::from_cache_bytes` that were not saved
+    /// by this version of the crate
+    InvalidCache(String),
+}
+
+impl WgslError {
+    /// The line in the shader source the error is on, if known.
+    pub fn line(&self) -> Option<usize> {
+        match self {
+            WgslError::InvalidIfBlock { line }
+            | WgslError::InvalidLoop { line }
+            | WgslError::InvalidConstantsBlock { line }
+            | WgslError::InvalidConstant { line }
+            | WgslError::DuplicateElse { line }
+            | WgslError::MismatchedEnd { line, .. }
+            | WgslError::UnexpectedElse { line }
+            | WgslError::UnexpectedEnd { line }
+            | WgslError::InvalidSection { line }
+            | WgslError::UnexpectedEndSection { line }
+            | WgslError::MisplacedGuard { line }
+            | WgslError::UnclosedSubstitution { line } => Some(*line),
+            WgslError::InLine { line, .. } => Some(*line),
+            WgslError::InvalidSyntax { error, .. } => error.line(),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn directives_are_recorded_as_written() {
+        let source = "//: if 0\nfn a() {}\n//: end\n//: if A&&B // note\nfn b() {}\n//: end if";
+        let shader = WgslShader::new(source).unwrap();
+
+        let directives = shader
+            .directives()
+            .into_iter()
+            .map(|d| (d.line, d.kind, d.parameter))
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            directives,
+            [
+                (1, "if".to_string(), "0".to_string()),
+                (3, "end".to_string(), String::new()),
+                (4, "if".to_string(), "A&&B // note".to_string()),
+                (6, "end".to_string(), "if".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn constant_conditions_are_folded() {
+        let shader = WgslShader::new("a\n//: if 0\nb\n//: end\nc").unwrap();
+        assert_eq!(shader.segment(), &WgslSegment::Text("a\nc\n".to_string()));
+
+        let shader = WgslShader::new("a\n//: if 1\nb\n//: else\nc\n//: end").unwrap();
+        assert_eq!(shader.segment(), &WgslSegment::Text("a\nb\n".to_string()));
+
+        let shader = WgslShader::new("//: if 2 + 2 == 4\n//: if X\nb\n//: end\n//: end").unwrap();
+        assert!(matches!(shader.segment(), WgslSegment::Conditional { .. }));
+    }
+
+    #[test]
+    fn folded_lines_are_not_traced() {
+        let mut workspace = WgslWorkspace::from_memory(
+            "",
+            &[(
+                "a.wgsl",
+                "a\n//: if 0\nb\n//: else\nc\n//: end\n//: if X\nd\n//: end",
+            )],
+        )
+        .unwrap();
+        workspace.set_global_bool("X", true);
+
+        let lines = workspace
+            .trace_shader("a.wgsl")
+            .unwrap()
+            .into_iter()
+            .map(|trace| trace.line)
+            .collect::<Vec<_>>();
+
+        assert_eq!(lines, [1, 5, 7, 8, 9]);
+    }
+
+    fn tangents_workspace(use_tangents: bool) -> WgslWorkspace {
+        let mut workspace = WgslWorkspace::from_memory(
+            "shaders",
+            &[
+                (
+                    "my-shader.wgsl",
+                    include_str!("../examples/shaders/my-shader.wgsl"),
+                ),
+                (
+                    "vertex.wgsl",
+                    include_str!("../examples/shaders/vertex.wgsl"),
+                ),
+            ],
+        )
+        .unwrap();
+
+        workspace.set_global_bool("USE_TANGENTS", use_tangents);
+        workspace
+    }
+
+    #[test]
+    fn trace_follows_the_taken_branch() {
+        for use_tangents in [false, true] {
+            let workspace = tangents_workspace(use_tangents);
+            let branch = match use_tangents {
+                true => WgslLineAction::Text,
+                false => WgslLineAction::Skipped,
+            };
+
+            let trace = workspace
+                .trace_shader("vertex.wgsl")
+                .unwrap()
+                .into_iter()
+                .map(|trace| (trace.line, trace.action))
+                .collect::<Vec<_>>();
+
+            assert_eq!(
+                trace,
+                [
+                    (1, WgslLineAction::Text),
+                    (2, WgslLineAction::Text),
+                    (3, WgslLineAction::Text),
+                    (4, WgslLineAction::Text),
+                    (5, WgslLineAction::Condition(use_tangents)),
+                    (6, branch.clone()),
+                    (7, branch),
+                    (8, WgslLineAction::Directive),
+                    (9, WgslLineAction::Text),
+                    (11, WgslLineAction::Text),
+                    (12, WgslLineAction::Text),
+                    (13, WgslLineAction::Text),
+                ]
+            );
+        }
+    }
+
+    #[test]
+    fn trace_does_not_include_included_shaders() {
+        let trace = tangents_workspace(true)
+            .trace_shader("my-shader.wgsl")
+            .unwrap();
+
+        assert_eq!(trace[0].line, 1);
+        assert_eq!(
+            trace[0].action,
+            WgslLineAction::Included(PathBuf::from("vertex.wgsl"))
+        );
+        assert_eq!(trace[1].line, 3);
+        assert_eq!(trace[1].action, WgslLineAction::Directive);
+        assert_eq!(trace.len(), 8);
+    }
+
+    #[test]
+    fn depends_on_resolves_includes_like_rendering() {
+        let workspace = WgslWorkspace::from_memory(
+            "/shaders",
+            &[
+                ("lib/a.wgsl", "//: include ./b.wgsl"),
+                ("lib/b.wgsl", "//: const X"),
+                ("lib/c.wgsl", "//: include ../d.wgsl"),
+                ("d.wgsl", "//: const Y"),
+                ("e.wgsl", "//: include lib/b.wgsl"),
+                ("f.wgsl", "//: include /shaders/d.wgsl"),
+            ],
+        )
+        .unwrap();
+
+        // Relative to the including shader
+        assert!(workspace.depends_on("lib/a.wgsl", "X").unwrap());
+        assert!(workspace.depends_on("lib/c.wgsl", "Y").unwrap());
+        // Relative to the root
+        assert!(workspace.depends_on("e.wgsl", "X").unwrap());
+        // Absolute, inside the root
+        assert!(workspace.depends_on("f.wgsl", "Y").unwrap());
+
+        assert!(!workspace.depends_on("lib/a.wgsl", "Y").unwrap());
+    }
+
+    #[test]
+    fn include_glob_writes_matches_in_sorted_order() {
+        let mut workspace = WgslWorkspace::from_memory(
+            "",
+            &[
+                ("effects/main.wgsl", "//: include_glob ./fx/*.wgsl"),
+                ("effects/fx/c.wgsl", "c"),
+                ("effects/fx/a.wgsl", "a"),
+                ("effects/fx/b.wgsl", "//: const B"),
+                ("effects/fx/d.txt", "d"),
+            ],
+        )
+        .unwrap();
+
+        assert!(workspace.depends_on("effects/main.wgsl", "B").unwrap());
+
+        workspace.set_global_i64("B", 1);
+
+        assert_eq!(
+            workspace.get_shader("effects/main.wgsl").unwrap(),
+            "a\nconst B = 1;\nc\n"
+        );
+    }
+
+    #[test]
+    fn depends_on_direct_conditional_and_included() {
+        let workspace = WgslWorkspace::from_memory(
+            "",
+            &[
+                ("direct.wgsl", "//: const_expr A B + 1"),
+                ("conditional.wgsl", "//: if C\nfn f() {}\n//: end"),
+                ("include.wgsl", "//: include conditional.wgsl"),
+                ("cycle_a.wgsl", "//: include cycle_b.wgsl"),
+                ("cycle_b.wgsl", "//: include cycle_a.wgsl\n//: const D"),
+            ],
+        )
+        .unwrap();
+
+        assert!(workspace.depends_on("direct.wgsl", "B").unwrap());
+        assert!(!workspace.depends_on("direct.wgsl", "A").unwrap());
+        assert!(workspace.depends_on("conditional.wgsl", "C").unwrap());
+        assert!(workspace.depends_on("include.wgsl", "C").unwrap());
+        assert!(!WgslShader::new("//: include conditional.wgsl")
+            .unwrap()
+            .depends_on("C"));
+
+        assert!(workspace.depends_on("cycle_a.wgsl", "D").unwrap());
+        assert!(!workspace.depends_on("cycle_a.wgsl", "E").unwrap());
+    }
+
+    #[test]
+    fn if_blocks_may_end_at_the_end_of_the_file() {
+        let shader = WgslShader::new("a\n//: if X\nb").unwrap();
+        assert!(matches!(shader.segment(), WgslSegment::Sequence(_)));
+
+        WgslShader::new("//: if X\na\n//: else\nb").unwrap();
+    }
+
+    #[test]
+    fn malformed_if_blocks_report_their_line() {
+        let error = WgslShader::new("a\n//: if X\nb\n//: endsection").unwrap_err();
+        assert!(matches!(error, WgslError::InvalidIfBlock { line: 2 }));
+        assert_eq!(error.line(), Some(2));
+
+        let error = WgslShader::new("//: if X\n//: else\n\n//: else\n//: end").unwrap_err();
+        assert!(matches!(error, WgslError::DuplicateElse { line: 4 }));
+
+        let error = WgslShader::new("//: if X\na\n//: end for").unwrap_err();
+        assert_eq!(error.line(), Some(3));
+    }
+
+    #[test]
+    fn preserving_keeps_the_original_source() {
+        let source = "//:guard  A\r\n\n  //:if B&&C // note\n\tfn f() {}\n//:end if\n\n";
+        let shader = WgslShader::new_preserving(source).unwrap();
+
+        assert_eq!(shader.original_source(), Some(source));
+        assert_eq!(WgslShader::new(source).unwrap().original_source(), None);
+    }
+
+    #[test]
+    fn substitutions_end_outside_strings() {
+        let mut workspace = WgslWorkspace::from_memory(
+            "",
+            &[
+                ("a.wgsl", "let x: ${USE_X ? \"tangent\" : \"none\"};"),
+                ("b.wgsl", "${\"a}b\"} ${\"c\\\"}\"}"),
+            ],
+        )
+        .unwrap();
+
+        workspace.set_global_bool("USE_X", true);
+        assert_eq!(workspace.get_shader("a.wgsl").unwrap(), "let x: tangent;\n");
+
+        workspace.set_global_bool("USE_X", false);
+        assert_eq!(workspace.get_shader("a.wgsl").unwrap(), "let x: none;\n");
+
+        assert_eq!(workspace.get_shader("b.wgsl").unwrap(), "a}b c\"}\n");
+    }
+
+    #[test]
+    fn const_expr_precision() {
+        let workspace = WgslWorkspace::from_memory(
+            "",
+            &[
+                (
+                    "a.wgsl",
+                    "//: const_expr THIRD: f32(6) 1.0 / 3.0\n//: const_expr TWO: f32(0) 2.4",
+                ),
+                ("b.wgsl", "//: const_expr SMALL: f32(2) 0.000001"),
+            ],
+        )
+        .unwrap();
+
+        assert_eq!(
+            workspace.get_shader("a.wgsl").unwrap(),
+            "const THIRD: f32 = 0.333333;\nconst TWO: f32 = 2.0;\n"
+        );
+        assert!(matches!(
+            workspace.get_shader("b.wgsl"),
+            Err(WgslError::RoundedToZero { precision: 2, .. })
+        ));
+    }
+
+    #[test]
+    fn int_overflow_modes() {
+        let eval = |int_overflow, source| {
+            let state = WgslWorkspaceState {
+                int_overflow,
+                ..Default::default()
+            };
+
+            state.eval_expr(source)
+        };
+
+        let max = "9223372036854775807 + 1";
+        let abs = "abs(-9223372036854775807 - 1)";
+
+        for source in [max, abs] {
+            assert!(matches!(
+                eval(IntOverflow::Checked, source),
+                Err(WgslError::ArithmeticOverflow)
+            ));
+        }
+
+        assert!(matches!(
+            eval(IntOverflow::Wrapping, max),
+            Ok(WgslLiteral::Integer(i64::MIN))
+        ));
+        assert!(matches!(
+            eval(IntOverflow::Wrapping, abs),
+            Ok(WgslLiteral::Integer(i64::MIN))
+        ));
+        assert!(matches!(
+            eval(IntOverflow::Saturating, max),
+            Ok(WgslLiteral::Integer(i64::MAX))
+        ));
+        assert!(matches!(
+            eval(IntOverflow::Saturating, abs),
+            Ok(WgslLiteral::Integer(i64::MAX))
+        ));
+        assert!(matches!(
+            eval(IntOverflow::Checked, "abs(-5)"),
+            Ok(WgslLiteral::Integer(5))
+        ));
+    }
+
+    #[test]
+    fn trailing_commas_empty_lists_and_zero_argument_calls() {
+        let mut workspace = WgslWorkspace::from_memory("", &[]).unwrap();
+        workspace.register_function("seven", |arguments| match arguments {
+            [] => Ok(WgslLiteral::Integer(7)),
+            _ => Err(WgslError::InvalidArgumentCount {
+                expected: 0,
+                found: arguments.len(),
+            }),
+        });
+
+        let eval = |source: &str| workspace.state().eval_expr(source);
+
+        assert!(matches!(
+            eval("clamp(70, 0, 64,)"),
+            Ok(WgslLiteral::Integer(64))
+        ));
+        assert!(matches!(
+            eval("[1, 2, 3,]"),
+            Ok(WgslLiteral::List(list)) if list.len() == 3
+        ));
+        assert!(matches!(eval("[]"), Ok(WgslLiteral::List(list)) if list.is_empty()));
+        assert!(matches!(eval("seven()"), Ok(WgslLiteral::Integer(7))));
+        assert!(eval("seven(1)").is_err());
+        assert!(matches!(
+            eval("abs()"),
+            Err(WgslError::InvalidArgumentCount {
+                expected: 1,
+                found: 0
+            })
+        ));
+        assert!(eval("[,]").is_err());
+        assert!(eval("clamp(1,,2)").is_err());
+    }
+
+    #[test]
+    fn formatting_is_idempotent() {
+        let source = "a\n\n  b\n//:if   X==1// note\n\n    c\n//:else\n//:const_expr  Y:f32(2)  X*2\n//:end\n//:if 0\nd\n//:end";
+        let formatted = WgslShader::format(source).unwrap();
+
+        assert_eq!(
+            formatted,
+            "a\n\n  b\n//: if X == 1\n\n    c\n//: else\n//: const_expr Y: f32(2) X * 2\n//: end\n//: if 0\nd\n//: end\n"
+        );
+        assert_eq!(WgslShader::format(&formatted).unwrap(), formatted);
+
+        let shader = WgslShader::new(source).unwrap();
+        let formatted = shader.format_source();
+        assert_eq!(
+            WgslShader::new(&formatted).unwrap().format_source(),
+            formatted
+        );
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn ast_json_reads_back_to_an_equal_tree() {
+        #[derive(serde::Deserialize)]
+        struct Ast {
+            guard: Option<WgslExpression>,
+            segment: WgslSegment,
+        }
+
+        let source = "//: guard FLAGS & BIT_2\n//: if X > 1.5 || !Y\nfn f() -> f32 { return ${Z:f}; }\n//: end\n//: for i in [1, 2,]\n//: const i\n//: end";
+        let shader = WgslShader::new(source).unwrap();
+        let json = shader.to_ast_json().unwrap();
+
+        assert!(json.contains(r#"{"Float":1.5}"#));
+        assert!(json.contains(r#""Or""#));
+
+        let ast: Ast = serde_json::from_str(&json).unwrap();
+        assert_eq!(ast.guard.as_ref(), shader.guard.as_ref());
+        assert_eq!(&ast.segment, shader.segment());
+    }
+
+    #[test]
+    fn rendering_twice_gives_the_same_output() {
+        let mut workspace = WgslWorkspace::from_memory(
+            "",
+            &[
+                ("lib.wgsl", "//: export X X + 1\n//: default Y 1"),
+                (
+                    "main.wgsl",
+                    "//: include lib.wgsl\n//: const X\n//: const Y\n//: for i in 0..2\n//: binding auto\n//: end",
+                ),
+            ],
+        )
+        .unwrap();
+        workspace.set_global_i64("X", 1);
+
+        let first = workspace.get_shader("main.wgsl").unwrap();
+        workspace.clear_cache();
+        let second = workspace.get_shader("main.wgsl").unwrap();
+
+        assert_eq!(first, second);
+        assert_eq!(
+            first,
+            "const X = 2;\nconst Y = 1;\n@group(0) @binding(0)\n@group(0) @binding(1)\n"
+        );
+        assert!(matches!(
+            workspace.state().get("X"),
+            Some(WgslLiteral::Integer(1))
+        ));
+        assert!(workspace.state().get("Y").is_none());
+    }
+
+    #[test]
+    fn comparisons_in_const_expr() {
+        let mut workspace = WgslWorkspace::from_memory(
+            "",
+            &[(
+                "a.wgsl",
+                "//: const_expr IS_HIGH QUALITY >= 3\n//: const_expr BOTH QUALITY > 1 && FAST\n//: const_expr EITHER QUALITY == 0 || !FAST",
+            )],
+        )
+        .unwrap();
+
+        workspace.set_global_i64("QUALITY", 3);
+        workspace.set_global_bool("FAST", true);
+        assert_eq!(
+            workspace.get_shader("a.wgsl").unwrap(),
+            "const IS_HIGH = true;\nconst BOTH = true;\nconst EITHER = false;\n"
+        );
+
+        workspace.set_global_i64("QUALITY", 0);
+        assert_eq!(
+            workspace.get_shader("a.wgsl").unwrap(),
+            "const IS_HIGH = false;\nconst BOTH = false;\nconst EITHER = true;\n"
+        );
+    }
+
+    #[test]
+    fn include_as_prefixes_constants() {
+        let mut workspace = WgslWorkspace::from_memory(
+            "",
+            &[
+                ("lighting.wgsl", "//: const X\n//: const_expr Y X * 2"),
+                (
+                    "main.wgsl",
+                    "//: include lighting.wgsl as light\n//: include lighting.wgsl\n//: const X",
+                ),
+            ],
+        )
+        .unwrap();
+        workspace.set_global_i64("X", 2);
+
+        assert_eq!(
+            workspace.get_shader("main.wgsl").unwrap(),
+            "const light_X = 2;\nconst light_Y = 4;\nconst X = 2;\nconst Y = 4;\nconst X = 2;\n"
+        );
+    }
+
+    #[test]
+    fn rendering_a_shader_whose_includes_are_cached() {
+        let workspace = WgslWorkspace::from_memory(
+            "",
+            &[
+                ("a.wgsl", "a"),
+                ("b.wgsl", "//: include a.wgsl\nb"),
+                ("c.wgsl", "//: include b.wgsl\n//: include a.wgsl\nc"),
+            ],
+        )
+        .unwrap();
+
+        assert_eq!(workspace.get_shader("a.wgsl").unwrap(), "a\n");
+        assert_eq!(workspace.get_shader("b.wgsl").unwrap(), "a\nb\n");
+        assert_eq!(workspace.get_shader("c.wgsl").unwrap(), "a\nb\na\nc\n");
+        assert_eq!(workspace.get_shader("c.wgsl").unwrap(), "a\nb\na\nc\n");
+    }
+
+    #[test]
+    fn float_truthiness_in_conditions() {
+        let mut workspace =
+            WgslWorkspace::from_memory("", &[("a.wgsl", "//: if F\nyes\n//: else\nno\n//: end")])
+                .unwrap();
+
+        workspace.set_global_f64("F", -0.0);
+        assert_eq!(workspace.get_shader("a.wgsl").unwrap(), "no\n");
+
+        workspace.set_global_f64("F", 1e-20);
+        assert_eq!(workspace.get_shader("a.wgsl").unwrap(), "yes\n");
+
+        workspace.set_global_f64("F", f64::NAN);
+        assert!(matches!(
+            workspace.get_shader("a.wgsl"),
+            Err(WgslError::NanCondition)
+        ));
+    }
+
+    #[test]
+    fn branch_conditions_of_nested_ifs() {
+        let shader = WgslShader::new(
+            "//: guard G\n//: if A\n//: if B\na\n//: else\n//: if C\nb\n//: end\n//: end\n//: end\n//: for i in 0..2\n//: if i == 1\nc\n//: end\n//: end\nx {{#if D}}d{{/if}}",
+        )
+        .unwrap();
+
+        let conditions = shader
+            .branch_conditions()
+            .iter()
+            .map(WgslExpression::to_source)
+            .collect::<Vec<_>>();
+
+        assert_eq!(conditions, ["G", "A", "B", "C", "i == 1", "D"]);
+    }
+
+    #[test]
+    fn deny_undefined_lists_every_undefined_variable() {
+        let mut workspace = WgslWorkspace::from_memory(
+            "",
+            &[
+                ("a.wgsl", "//: if DEFINED\na\n//: end"),
+                ("b.wgsl", "//: if DEFINED && MISSING\nb\n//: end"),
+            ],
+        )
+        .unwrap();
+        workspace.set_global_bool("DEFINED", false);
+        workspace.set_deny_undefined(true);
+
+        let Err(WgslError::UndefinedVariables(undefined)) = workspace.validate_all() else {
+            panic!("`MISSING` is not defined");
+        };
+
+        assert_eq!(
+            undefined,
+            [(PathBuf::from("b.wgsl"), "MISSING".to_string())]
+        );
+
+        workspace.set_global_bool("MISSING", true);
+        workspace.validate_all().unwrap();
+    }
+
+    #[test]
+    fn lossy_integer_division_warns() {
+        let workspace = WgslWorkspace::from_memory(
+            "",
+            &[(
+                "a.wgsl",
+                "//: const_expr HALF 5 / 2\n//: const_expr EVEN 4 / 2",
+            )],
+        )
+        .unwrap();
+
+        assert_eq!(
+            workspace.get_shader("a.wgsl").unwrap(),
+            "const HALF = 2;\nconst EVEN = 2;\n"
+        );
+        assert_eq!(
+            workspace.warnings("a.wgsl").unwrap(),
+            [WgslWarning::LossyDivision { left: 5, right: 2 }]
+        );
+    }
+
+    #[test]
+    fn dead_and_live_flags() {
+        let workspace = WgslWorkspace::from_memory(
+            "",
+            &[
+                ("dead.wgsl", "//: if FLAG\na\n//: else\na\n//: end"),
+                ("live.wgsl", "//: if FLAG\na\n//: else\nb\n//: end"),
+            ],
+        )
+        .unwrap();
+
+        assert!(!workspace.flag_affects_output("dead.wgsl", "FLAG").unwrap());
+        assert!(workspace.flag_affects_output("live.wgsl", "FLAG").unwrap());
+        assert!(workspace.state().get("FLAG").is_none());
+    }
+
+    #[test]
+    fn errors_report_their_line() {
+        let error =
+            WgslWorkspace::from_memory("", &[("a.wgsl", "fn a() {}\n\n//:else\n")]).unwrap_err();
+        assert_eq!(error.line(), Some(3));
+
+        let workspace = WgslWorkspace::from_memory("", &[("a.wgsl", "")]).unwrap();
+        let error = workspace.get_shader("b.wgsl").unwrap_err();
+        assert!(matches!(error, WgslError::NotFound));
+        assert_eq!(error.line(), None);
+    }
+
+    #[test]
+    fn features_are_tested_by_membership() {
+        let mut workspace = WgslWorkspace::from_memory(
+            "",
+            &[(
+                "a.wgsl",
+                "//: if feature(\"FLOAT16\")\nenable f16;\n//: end\n//: if feature(\"STORAGE_TEXTURES\")\nfn storage() {}\n//: end",
+            )],
+        )
+        .unwrap();
+
+        workspace.set_features(&["FLOAT16"]);
+        assert_eq!(workspace.get_shader("a.wgsl").unwrap(), "enable f16;\n");
+
+        workspace.set_features(&["STORAGE_TEXTURES"]);
+        assert_eq!(workspace.get_shader("a.wgsl").unwrap(), "fn storage() {}\n");
+
+        workspace.set_features(&[]);
+        assert_eq!(workspace.get_shader("a.wgsl").unwrap(), "");
+    }
+
+    #[test]
+    fn parse_trees_can_be_compared() {
+        let shader = WgslShader::new_preserving("//: if 1\nfn a() {}\n//: end").unwrap();
+        assert_eq!(
+            shader.segment(),
+            &WgslSegment::Conditional {
+                condition: WgslExpression::Literal(WgslLiteral::Integer(1)),
+                if_true: Box::new(WgslSegment::Text("fn a() {}\n".to_string())),
+                if_false: None,
+            }
+        );
+
+        let shader = WgslShader::new("//: if X\nfn a() {}\n//: end").unwrap();
+        assert_ne!(
+            shader.segment(),
+            &WgslSegment::Conditional {
+                condition: WgslExpression::Reference("Y".to_string()),
+                if_true: Box::new(WgslSegment::Text("fn a() {}\n".to_string())),
+                if_false: None,
+            }
+        );
+    }
+
+    #[test]
+    fn constants_cb_follows_the_taken_branches() {
+        let mut workspace = WgslWorkspace::from_memory(
+            "",
+            &[(
+                "a.wgsl",
+                "//: const A\n//: if A > 1\n//: const_expr B A * 2\n//: else\n//: const_expr C A * 3\n//: end\n//: const_expr D 0.5",
+            )],
+        )
+        .unwrap();
+        workspace.set_global_i64("A", 2);
+
+        let mut constants = Vec::new();
+        let shader = workspace
+            .get_shader_with_constants_cb("a.wgsl", |name, value| {
+                constants.push((name.to_string(), value))
+            })
+            .unwrap();
+
+        assert_eq!(
+            constants,
+            [
+                ("A".to_string(), WgslLiteral::Integer(2)),
+                ("B".to_string(), WgslLiteral::Integer(4)),
+                ("D".to_string(), WgslLiteral::Float(0.5)),
+            ]
+        );
+        assert_eq!(shader, workspace.get_shader("a.wgsl").unwrap());
+    }
+
+    #[test]
+    fn includes_behind_conditions_are_detailed() {
+        let workspace = WgslWorkspace::from_memory(
+            "",
+            &[
+                (
+                    "a.wgsl",
+                    "//: include b.wgsl\n//: if X\n//: include c.wgsl\n//: include b.wgsl\n//: end",
+                ),
+                ("b.wgsl", "fn b() {}"),
+                ("c.wgsl", "//: include d.wgsl"),
+                ("d.wgsl", "fn d() {}"),
+            ],
+        )
+        .unwrap();
+
+        assert_eq!(
+            workspace.dependencies_detailed("a.wgsl").unwrap(),
+            [
+                (PathBuf::from("b.wgsl"), true),
+                (PathBuf::from("c.wgsl"), false),
+                (PathBuf::from("d.wgsl"), false),
+            ]
+        );
+    }
+
+    #[test]
+    fn constant_names_are_validated() {
+        let long = "A".repeat(MAX_IDENTIFIER_LENGTH + 1);
+        let shaders = [
+            ("fn.wgsl", "//: const fn".to_string()),
+            ("empty.wgsl", "//: const".to_string()),
+            ("long.wgsl", format!("//: const {long}")),
+        ];
+        let shaders: Vec<_> = shaders.iter().map(|(p, s)| (*p, s.as_str())).collect();
+
+        let mut workspace = WgslWorkspace::from_memory("", &shaders).unwrap();
+        workspace.set_global_i64("fn", 1);
+        workspace.set_global_i64(&long, 1);
+
+        for path in ["fn.wgsl", "empty.wgsl", "long.wgsl"] {
+            let error = workspace.get_shader(path).unwrap_err();
+            assert!(
+                matches!(error, WgslError::InvalidIdentifier(_)),
+                "{path}: {error:?}"
+            );
+        }
+
+        assert!(matches!(
+            WgslExpression::new(&long),
+            Err(WgslError::InvalidSyntax { error, .. })
+                if matches!(*error, WgslError::InvalidIdentifier(_))
+        ));
+    }
+
+    #[test]
+    fn merged_workspaces_include_each_other() {
+        let mut workspace =
+            WgslWorkspace::from_memory("", &[("a.wgsl", "//: include lib/b.wgsl\n//: const A")])
+                .unwrap();
+        workspace.set_global_i64("A", 1);
+
+        let mut other = WgslWorkspace::from_memory("", &[("lib/b.wgsl", "//: const B")]).unwrap();
+        other.set_global_i64("A", 2);
+        other.set_global_i64("B", 3);
+
+        workspace.merge(other).unwrap();
+        assert_eq!(
+            workspace.get_shader("a.wgsl").unwrap(),
+            "const B = 3;\nconst A = 1;\n"
+        );
+
+        let other = WgslWorkspace::from_memory("", &[("a.wgsl", "")]).unwrap();
+        assert!(matches!(
+            workspace.merge(other),
+            Err(WgslError::DuplicateShader(path)) if path == Path::new("a.wgsl")
+        ));
+    }
+
+    #[test]
+    fn labeled_ends_must_match_their_block() {
+        let shader = WgslShader::new(
+            "//: for i in 0..2\n//: if X\nb\n//: end if\n//: end for\n//: if X\nc\n//: end",
+        );
+        assert!(shader.is_ok());
+
+        let error =
+            WgslShader::new("//: for i in 0..2\n//: if X\nb\n//: end for\n//: end if").unwrap_err();
+        assert!(matches!(
+            error,
+            WgslError::MismatchedEnd { ref expected, ref found, line: 4 }
+                if expected == "if" && found == "for"
+        ));
+    }
+
+    #[test]
+    fn guards_skip_the_whole_shader() {
+        let mut workspace =
+            WgslWorkspace::from_memory("", &[("a.wgsl", "//: guard USE_A\nfn a() {}")]).unwrap();
+
+        workspace.set_global_bool("USE_A", true);
+        assert_eq!(workspace.get_shader("a.wgsl").unwrap(), "fn a() {}\n");
+        assert_eq!(
+            workspace.try_get_shader("a.wgsl").unwrap().as_deref(),
+            Some("fn a() {}\n")
+        );
+
+        workspace.set_global_bool("USE_A", false);
+        assert_eq!(workspace.get_shader("a.wgsl").unwrap(), "");
+        assert_eq!(workspace.try_get_shader("a.wgsl").unwrap(), None);
+
+        assert!(matches!(
+            WgslShader::new("fn a() {}\n//: guard USE_A"),
+            Err(WgslError::MisplacedGuard { line: 2 })
+        ));
+    }
+
+    #[test]
+    fn required_globals_infer_their_type() {
+        let source = "//: if USE_SHADOWS && PI > 3\n//: for i in 0..CASCADES\nfn cascade_${i}() {}\n//: end\n//: const_expr SIZE BASE * 2\n//: end\n//: const NAME";
+        let shader = WgslShader::new(source).unwrap();
+
+        assert_eq!(
+            shader.required_globals(),
+            [
+                ("USE_SHADOWS".to_string(), WgslInferredType::Bool),
+                ("CASCADES".to_string(), WgslInferredType::Integer),
+                ("BASE".to_string(), WgslInferredType::Number),
+                ("NAME".to_string(), WgslInferredType::Unknown),
+            ]
+        );
+
+        let mut workspace = WgslWorkspace::from_memory("", &[("a.wgsl", source)]).unwrap();
+        workspace.set_global_bool("USE_SHADOWS", true);
+        workspace.set_global_i64("CASCADES", 2);
+        workspace.set_global_i64("BASE", 3);
+        workspace.set_global_i64("NAME", 1);
+        assert_eq!(
+            workspace.get_shader("a.wgsl").unwrap(),
+            "fn cascade_0() {}\nfn cascade_1() {}\nconst SIZE = 6;\nconst NAME = 1;\n"
+        );
+
+        // Names are not substituted, so this is rejected when parsing
+        assert!(matches!(
+            WgslShader::new("//: const_expr SIZE${i} 2"),
+            Err(WgslError::InLine { line: 1, error, .. })
+                if matches!(*error, WgslError::InvalidIdentifier(_))
+        ));
+    }
+
+    #[test]
+    fn errors_keep_the_raw_line() {
+        let error = WgslShader::new("fn a() {}\n//:  fi   USE_X").unwrap_err();
+        assert!(matches!(
+            &error,
+            WgslError::InLine { line: 2, source, error }
+                if source == "//:  fi   USE_X"
+                    && matches!(**error, WgslError::UnknownOperation(ref op) if op == "fi")
+        ));
+        assert!(format!("{error:?}").contains("//:  fi   USE_X"));
+
+        let error = WgslShader::new("//: if 1 +\nb\n//: end").unwrap_err();
+        assert!(matches!(
+            &error,
+            WgslError::InLine { line: 1, source, .. } if source == "//: if 1 +"
+        ));
+    }
+
+    #[test]
+    fn doc_comments_precede_their_constant() {
+        let mut workspace = WgslWorkspace::from_memory(
+            "",
+            &[(
+                "a.wgsl",
+                "//: const MAX_LIGHTS /// maximum lights\n//: const_expr HALF MAX_LIGHTS / 2 /// half of them\n//: const PLAIN",
+            )],
+        )
+        .unwrap();
+        workspace.set_global_i64("MAX_LIGHTS", 8);
+        workspace.set_global_i64("PLAIN", 1);
+
+        assert_eq!(
+            workspace.get_shader("a.wgsl").unwrap(),
+            "// maximum lights\nconst MAX_LIGHTS = 8;\n// half of them\nconst HALF = 4;\nconst PLAIN = 1;\n"
+        );
+    }
+
+    #[test]
+    fn both_separators_find_the_same_shader() {
+        let workspace = WgslWorkspace::from_memory(
+            "C:\\shaders",
+            &[
+                ("lib\\common.wgsl", "fn common() {}"),
+                ("a.wgsl", "//: include C:\\shaders\\lib\\common.wgsl"),
+                ("b.wgsl", "//: include C:/shaders/lib/common.wgsl"),
+                ("c.wgsl", "//: include lib/common.wgsl"),
+                ("lib/d.wgsl", "//: include .\\common.wgsl"),
+            ],
+        )
+        .unwrap();
+
+        let common = workspace.get_shader("lib/common.wgsl").unwrap();
+        assert_eq!(common, "fn common() {}\n");
+        assert_eq!(workspace.get_shader("lib\\common.wgsl").unwrap(), common);
+
+        for path in ["a.wgsl", "b.wgsl", "c.wgsl", "lib\\d.wgsl"] {
+            assert_eq!(workspace.get_shader(path).unwrap(), common, "{path}");
+        }
+    }
+
+    #[test]
+    fn packed_flags_use_named_bits() {
+        let mut workspace = WgslWorkspace::from_memory(
+            "",
+            &[(
+                "a.wgsl",
+                "//: if FLAGS & USE_SHADOWS\nfn shadows() {}\n//: end\n//: if FLAGS & USE_FOG\nfn fog() {}\n//: end",
+            )],
+        )
+        .unwrap();
+        workspace.define_flag("USE_SHADOWS", 0).unwrap();
+        workspace.define_flag("USE_FOG", 5).unwrap();
+
+        workspace.set_global_u64("FLAGS", 0b100000);
+        assert_eq!(workspace.get_shader("a.wgsl").unwrap(), "fn fog() {}\n");
+
+        workspace.set_global_u64("FLAGS", 0b100001);
+        assert_eq!(
+            workspace.get_shader("a.wgsl").unwrap(),
+            "fn shadows() {}\nfn fog() {}\n"
+        );
+
+        assert!(matches!(
+            workspace.define_flag("USE_TOO_MUCH", 64),
+            Err(WgslError::InvalidShift { amount: 64 })
+        ));
+    }
+
+    #[test]
+    fn identical_variants_are_returned_once() {
+        let workspace = WgslWorkspace::from_memory(
+            "",
+            &[(
+                "a.wgsl",
+                "//: if A || B\nx\n//: end\n//: if A && B\ny\n//: end",
+            )],
+        )
+        .unwrap();
+
+        assert_eq!(
+            workspace.distinct_variants("a.wgsl", &["A", "B"]).unwrap(),
+            ["", "x\n", "x\ny\n"]
+        );
+    }
+
+    #[test]
+    fn exports_of_included_shaders_are_local() {
+        let workspace = WgslWorkspace::from_memory(
+            "",
+            &[
+                ("config.wgsl", "//: export LIGHTS 8"),
+                (
+                    "a.wgsl",
+                    "//: include config.wgsl\n//: const LIGHTS\n//: if LIGHTS > 4\nfn many() {}\n//: end",
+                ),
+                ("b.wgsl", "//: const LIGHTS\n//: include config.wgsl"),
+            ],
+        )
+        .unwrap();
+
+        assert_eq!(
+            workspace.get_shader("a.wgsl").unwrap(),
+            "const LIGHTS = 8;\nfn many() {}\n"
+        );
+
+        // The export is only visible after the include and never global
+        assert!(workspace.get_shader("b.wgsl").is_err());
+        assert!(!workspace.state().global_variables.contains_key("LIGHTS"));
+    }
+
+    #[test]
+    fn full_caches_forget_the_least_recently_used_shader() {
+        let mut workspace = WgslWorkspace::from_memory(
+            "",
+            &[
+                ("a.wgsl", "fn a() {}"),
+                ("b.wgsl", "fn b() {}"),
+                ("c.wgsl", "fn c() {}"),
+            ],
+        )
+        .unwrap();
+        workspace.set_cache_capacity(2);
+
+        let cached = |workspace: &WgslWorkspace| {
+            let mut paths: Vec<_> = workspace.cache().entries.keys().cloned().collect();
+            paths.sort();
+            paths
+        };
+
+        workspace.get_shader("a.wgsl").unwrap();
+        workspace.get_shader("b.wgsl").unwrap();
+        workspace.get_shader("a.wgsl").unwrap();
+        workspace.get_shader("c.wgsl").unwrap();
+        assert_eq!(cached(&workspace), ["a.wgsl", "c.wgsl"].map(PathBuf::from));
+
+        assert_eq!(workspace.get_shader("b.wgsl").unwrap(), "fn b() {}\n");
+        assert_eq!(cached(&workspace), ["b.wgsl", "c.wgsl"].map(PathBuf::from));
+
+        workspace.set_cache_capacity(0);
+        assert!(cached(&workspace).is_empty());
+    }
+
+    #[test]
+    fn progress_callbacks_can_cancel_parsing() {
+        let source = "//: if X\nfn a() {}\n//: end\n".repeat(1000);
+
+        let mut reported = Vec::new();
+        let shader = WgslShader::new_with_progress_cb(&source, 1000, |line| {
+            reported.push(line);
+            true
+        });
+        assert!(shader.is_ok());
+        assert_eq!(reported, [1000, 2000, 3000]);
+
+        let mut calls = 0;
+        let shader = WgslShader::new_with_progress_cb(&source, 100, |line| {
+            calls += 1;
+            line < 500
+        });
+        assert!(matches!(shader, Err(WgslError::Cancelled)));
+        assert_eq!(calls, 5);
+    }
+
+    #[test]
+    fn defaults_only_apply_to_unset_variables() {
+        let mut workspace = WgslWorkspace::from_memory(
+            "",
+            &[
+                (
+                    "a.wgsl",
+                    "//: default USE_X false\n//: if USE_X\nfn x() {}\n//: end\n//: const USE_X",
+                ),
+                (
+                    "b.wgsl",
+                    "//: export COUNT 2\n//: default COUNT 5\n//: const COUNT",
+                ),
+            ],
+        )
+        .unwrap();
+
+        assert_eq!(
+            workspace.get_shader("a.wgsl").unwrap(),
+            "const USE_X = false;\n"
+        );
+
+        workspace.set_global_bool("USE_X", true);
+        assert_eq!(
+            workspace.get_shader("a.wgsl").unwrap(),
+            "fn x() {}\nconst USE_X = true;\n"
+        );
+
+        assert_eq!(
+            workspace.get_shader("b.wgsl").unwrap(),
+            "const COUNT = 2;\n"
+        );
+    }
+
+    #[test]
+    fn defines_can_use_earlier_defines() {
+        let mut workspace = WgslWorkspace::from_memory("", &[("a.wgsl", "")]).unwrap();
+        workspace
+            .load_defines("# Quality settings\nSAMPLES = 16\n\nHALF = SAMPLES / 2\nHIGH_QUALITY = HALF >= 8\n")
+            .unwrap();
+
+        let globals = &workspace.state().global_variables;
+        assert_eq!(globals["SAMPLES"], 16);
+        assert_eq!(globals["HALF"], 8);
+        assert_eq!(globals["HIGH_QUALITY"], true);
+
+        let error = workspace
+            .load_defines("OTHER = 1\nBROKEN = UNDEFINED + 1")
+            .unwrap_err();
+        assert_eq!(error.line(), Some(2));
+        assert!(!workspace.state().global_variables.contains_key("OTHER"));
+    }
+
+    #[test]
+    fn bindings_are_numbered_per_group() {
+        let mut workspace = WgslWorkspace::from_memory(
+            "",
+            &[
+                (
+                    "a.wgsl",
+                    "//: binding auto\nvar<uniform> camera: Camera;\n//: include b.wgsl\n//: group 1\n//: binding auto\nvar color: texture_2d<f32>;\n//: binding albedo\nvar albedo: texture_2d<f32>;\n//: binding auto\nvar normal: texture_2d<f32>;",
+                ),
+                ("b.wgsl", "//: binding auto\nvar<uniform> time: f32;"),
+            ],
+        )
+        .unwrap();
+        workspace.set_global_i64("ALBEDO_BINDING", 4);
+
+        let expected = "@group(0) @binding(0)\nvar<uniform> camera: Camera;\n@group(0) @binding(1)\nvar<uniform> time: f32;\n@group(1) @binding(0)\nvar color: texture_2d<f32>;\n@group(1) @binding(4)\nvar albedo: texture_2d<f32>;\n@group(1) @binding(1)\nvar normal: texture_2d<f32>;\n";
+        assert_eq!(workspace.get_shader("a.wgsl").unwrap(), expected);
+
+        // Every render starts at group 0, binding 0 again
+        workspace.clear_cache();
+        assert_eq!(workspace.get_shader("a.wgsl").unwrap(), expected);
+        assert_eq!(workspace.get_shader("a.wgsl").unwrap(), expected);
+        assert_eq!(
+            workspace.get_shader("b.wgsl").unwrap(),
+            "@group(0) @binding(0)\nvar<uniform> time: f32;\n"
+        );
+    }
+
+    #[test]
+    fn inline_conditionals_pick_a_branch() {
+        let mut workspace = WgslWorkspace::from_memory(
+            "",
+            &[
+                (
+                    "a.wgsl",
+                    "var color: {{#if HDR}}vec4<f32>{{#else}}vec3<f32>{{/if}};\nvar a: {{#if HDR}}{{#if ALPHA}}f32{{#else}}u32{{/if}}{{/if}};",
+                ),
+                (
+                    "b.wgsl",
+                    "fn b() { let a = array<u32, 2>(1u, 2u); }\nlet s = \"{{ x }}\";\n// \\{{#if X}}",
+                ),
+            ],
+        )
+        .unwrap();
+        workspace.set_global_bool("ALPHA", true);
+
+        workspace.set_global_bool("HDR", true);
+        assert_eq!(
+            workspace.get_shader("a.wgsl").unwrap(),
+            "var color: vec4<f32>;\nvar a: f32;\n"
+        );
+
+        workspace.set_global_bool("HDR", false);
+        assert_eq!(
+            workspace.get_shader("a.wgsl").unwrap(),
+            "var color: vec3<f32>;\nvar a: ;\n"
+        );
+
+        assert_eq!(
+            workspace.get_shader("b.wgsl").unwrap(),
+            "fn b() { let a = array<u32, 2>(1u, 2u); }\nlet s = \"{{ x }}\";\n// {{#if X}}\n"
+        );
+
+        assert!(matches!(
+            WgslShader::new("a {{#if X}}b"),
+            Err(WgslError::InvalidIfBlock { line: 1 })
+        ));
+    }
+
+    #[test]
+    fn extra_globals_are_the_difference() {
+        let workspace = WgslWorkspace::from_memory(
+            "",
+            &[
+                ("a.wgsl", "//: if SHARED && ONLY_A\nfn a() {}\n//: end"),
+                (
+                    "b.wgsl",
+                    "//: include c.wgsl\n//: default LOCAL 1\n//: if SHARED && LOCAL\nfn b() {}\n//: end",
+                ),
+                ("c.wgsl", "//: const ONLY_B"),
+            ],
+        )
+        .unwrap();
+
+        assert_eq!(
+            workspace.extra_globals("a.wgsl", "b.wgsl").unwrap(),
+            HashSet::from(["ONLY_B".to_string()])
+        );
+        assert_eq!(
+            workspace.extra_globals("b.wgsl", "a.wgsl").unwrap(),
+            HashSet::from(["ONLY_A".to_string()])
+        );
+        assert!(workspace
+            .extra_globals("a.wgsl", "a.wgsl")
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn glob_patterns_match_without_exponential_backtracking() {
+        for (pattern, name, expected) in [
+            ("*.wgsl", "a.wgsl", true),
+            ("*.wgsl", ".wgsl", true),
+            ("*.wgsl", "a.wgs", false),
+            ("a?c", "abc", true),
+            ("a?c", "ac", false),
+            ("*", "", true),
+            ("", "a", false),
+            ("a*b*c", "aXbYbZc", true),
+            ("a*b*c", "aXbYbZ", false),
+            ("**x", "yyx", true),
+            ("é*", "éa", true),
+            ("?", "é", true),
+        ] {
+            assert_eq!(glob_matches(pattern, name), expected, "{pattern} {name}");
+        }
+
+        // Exponential with naive backtracking
+        let name = "a".repeat(10_000);
+        assert!(!glob_matches("*a*a*a*a*a*a*a*a*b", &name));
+        assert!(glob_matches("*a*a*a*a*a*a*a*a*a", &name));
+    }
+
+    #[test]
+    fn include_prefixes_are_identifiers() {
+        let workspace = WgslWorkspace::from_memory(
+            "",
+            &[
+                ("a.wgsl", "//: include my shaders as v2/x.wgsl"),
+                ("my shaders as v2/x.wgsl", "fn x() {}"),
+                (
+                    "b.wgsl",
+                    "//: include c.wgsl as light\n//: include c.wgsl#part",
+                ),
+                ("c.wgsl", "//: section part\nfn part() {}\n//: endsection"),
+            ],
+        )
+        .unwrap();
+
+        assert_eq!(workspace.get_shader("a.wgsl").unwrap(), "fn x() {}\n");
+        assert_eq!(
+            workspace.get_shader("b.wgsl").unwrap(),
+            "fn part() {}\nfn part() {}\n"
+        );
+
+        for prefix in ["1x", "my-prefix", "a b", "__x"] {
+            let source = format!("fn a() {{}}\n//: include c.wgsl as {prefix}");
+            let error = WgslShader::new(&source).unwrap_err();
+
+            assert!(
+                matches!(
+                    &error,
+                    WgslError::InLine { line: 2, error, .. }
+                        if matches!(**error, WgslError::InvalidIdentifier(_))
+                ),
+                "{prefix}: {error:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn variants_have_their_own_limit() {
+        let mut workspace = WgslWorkspace::from_memory(
+            "",
+            &[(
+                "a.wgsl",
+                "//: for i in 0..3\n//: if A\na${i}\n//: end\n//: end",
+            )],
+        )
+        .unwrap();
+
+        // The unroll limit only applies to loops
+        workspace.set_max_unroll(3);
+        assert_eq!(
+            workspace.distinct_variants("a.wgsl", &["A", "B"]).unwrap(),
+            ["", "a0\na1\na2\n"]
+        );
+
+        workspace.set_max_variants(2);
+        assert!(matches!(
+            workspace.distinct_variants("a.wgsl", &["A", "B"]),
+            Err(WgslError::TooManyVariants { count: 4, limit: 2 })
+        ));
+        assert_eq!(
+            workspace.distinct_variants("a.wgsl", &["A"]).unwrap().len(),
+            2
+        );
+
+        workspace.set_max_unroll(2);
+        assert!(matches!(
+            workspace.distinct_variants("a.wgsl", &["A"]),
+            Err(WgslError::LoopTooLarge { count: 3, limit: 2 })
+        ));
+    }
 }
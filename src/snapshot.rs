@@ -0,0 +1,144 @@
+//! The format of [`WgslWorkspace::to_cache_bytes`](crate::WgslWorkspace::to_cache_bytes),
+//! a version string followed by a [`Snapshot`] in `postcard`.
+
+use std::{
+    collections::{HashMap, HashSet},
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    expression::{IntOverflow, WgslLiteral},
+    WgslCacheCell, WgslError, WgslShader, WgslShaderCache, WgslWorkspace, WgslWorkspaceState,
+};
+
+/// Snapshots of other versions are rejected, the parsed tree may have
+/// changed between them. The number is for changes to the snapshot or the
+/// parsed tree within a version.
+const VERSION: &str = concat!(env!("CARGO_PKG_NAME"), " ", env!("CARGO_PKG_VERSION"), " 6");
+
+/// Borrowed version of [`Snapshot`], the fields have to stay in the same
+/// order because `postcard` doesn't store their names.
+#[derive(Serialize)]
+struct SnapshotRef<'a> {
+    root: &'a Path,
+    shaders: &'a HashMap<PathBuf, WgslShader>,
+    global_variables: &'a HashMap<String, WgslLiteral>,
+    features: &'a HashSet<String>,
+    int_overflow: IntOverflow,
+    max_unroll: usize,
+    max_variants: usize,
+    deny_undefined: bool,
+}
+
+#[derive(Deserialize)]
+struct Snapshot {
+    root: PathBuf,
+    shaders: HashMap<PathBuf, WgslShader>,
+    global_variables: HashMap<String, WgslLiteral>,
+    features: HashSet<String>,
+    int_overflow: IntOverflow,
+    max_unroll: usize,
+    max_variants: usize,
+    deny_undefined: bool,
+}
+
+fn invalid(error: postcard::Error) -> WgslError {
+    WgslError::InvalidCache(error.to_string())
+}
+
+pub(crate) fn encode(workspace: &WgslWorkspace) -> Result<Vec<u8>, WgslError> {
+    let mut bytes = postcard::to_allocvec(VERSION).map_err(invalid)?;
+
+    bytes.extend(
+        postcard::to_allocvec(&SnapshotRef {
+            root: &workspace.root,
+            shaders: &workspace.shaders,
+            global_variables: &workspace.state.global_variables,
+            features: &workspace.state.features,
+            int_overflow: workspace.state.int_overflow,
+            max_unroll: workspace.max_unroll,
+            max_variants: workspace.max_variants,
+            deny_undefined: workspace.deny_undefined,
+        })
+        .map_err(invalid)?,
+    );
+
+    Ok(bytes)
+}
+
+pub(crate) fn decode(bytes: &[u8]) -> Result<WgslWorkspace, WgslError> {
+    let (version, bytes) = postcard::take_from_bytes::<&str>(bytes).map_err(invalid)?;
+
+    if version != VERSION {
+        return Err(WgslError::InvalidCache(format!(
+            "saved by {version}, expected {VERSION}"
+        )));
+    }
+
+    let snapshot: Snapshot = postcard::from_bytes(bytes).map_err(invalid)?;
+
+    // Built-in functions and variables come from this build
+    let mut state = WgslWorkspaceState::default();
+    state.global_variables.extend(snapshot.global_variables);
+    state.features = snapshot.features;
+    state.int_overflow = snapshot.int_overflow;
+
+    Ok(WgslWorkspace {
+        state,
+        root: snapshot.root,
+        shaders: snapshot.shaders,
+        max_unroll: snapshot.max_unroll,
+        max_variants: snapshot.max_variants,
+        deny_undefined: snapshot.deny_undefined,
+        cache: WgslCacheCell::new(WgslShaderCache::new()),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn restored_workspaces_render_the_same() {
+        let shaders = [
+            (
+                "my-shader.wgsl",
+                include_str!("../examples/shaders/my-shader.wgsl"),
+            ),
+            (
+                "vertex.wgsl",
+                include_str!("../examples/shaders/vertex.wgsl"),
+            ),
+            (
+                "features.wgsl",
+                "//: if feature(\"FLOAT16\")\nenable f16;\n//: end\n//: const_expr BIG 9223372036854775807 + 1",
+            ),
+        ];
+
+        let mut workspace = WgslWorkspace::from_memory("shaders", &shaders).unwrap();
+        workspace.set_global_bool("USE_TANGENTS", true);
+        workspace.set_features(&["FLOAT16"]);
+        workspace.set_int_overflow(IntOverflow::Wrapping);
+
+        let restored =
+            WgslWorkspace::from_cache_bytes(&workspace.to_cache_bytes().unwrap()).unwrap();
+        assert!(restored.is_up_to_date(&shaders));
+
+        for (path, _) in shaders {
+            assert_eq!(
+                restored.get_shader(path).unwrap(),
+                workspace.get_shader(path).unwrap(),
+                "{path}"
+            );
+        }
+
+        let mut bytes = workspace.to_cache_bytes().unwrap();
+        bytes[1] ^= 1;
+        assert!(matches!(
+            WgslWorkspace::from_cache_bytes(&bytes),
+            Err(WgslError::InvalidCache(_))
+        ));
+    }
+}
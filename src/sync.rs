@@ -0,0 +1,201 @@
+//! A [`WgslWorkspace`] that can be changed while other threads render
+//! shaders from it.
+
+use std::{
+    collections::HashMap,
+    path::PathBuf,
+    sync::{PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard},
+};
+
+use crate::{
+    expression::{IntOverflow, WgslFunction, WgslLiteral},
+    WgslError, WgslWorkspace,
+};
+
+/// Shares a [`WgslWorkspace`] between threads, e.g. in an `Arc`.
+///
+/// The workspace is behind a single `RwLock`: any number of threads can
+/// render at the same time, and a setter waits until every render that
+/// started before it has finished. A render never sees half of an update,
+/// all variables it reads come from the same point in time. Rendered shaders
+/// are cached like in [`WgslWorkspace::get_shader`], the cache has its own
+/// lock that is only held to look up or store a shader.
+///
+/// Methods that are not wrapped can be used through
+/// [`SyncWgslWorkspace::read`] and [`SyncWgslWorkspace::write`].
+#[derive(Debug)]
+pub struct SyncWgslWorkspace {
+    workspace: RwLock<WgslWorkspace>,
+}
+
+impl SyncWgslWorkspace {
+    pub fn new(workspace: WgslWorkspace) -> Self {
+        Self {
+            workspace: RwLock::new(workspace),
+        }
+    }
+
+    pub fn into_inner(self) -> WgslWorkspace {
+        self.workspace
+            .into_inner()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Locks the workspace for reading, setters on other threads wait until
+    /// the guard is dropped.
+    pub fn read(&self) -> RwLockReadGuard<'_, WgslWorkspace> {
+        self.workspace
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Locks the workspace for writing, waiting for every reader.
+    pub fn write(&self) -> RwLockWriteGuard<'_, WgslWorkspace> {
+        self.workspace
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    pub fn get_shader(&self, path: impl Into<PathBuf>) -> Result<String, WgslError> {
+        self.read().get_shader(path)
+    }
+
+    pub fn get_shader_into(
+        &self,
+        path: impl Into<PathBuf>,
+        output: &mut String,
+    ) -> Result<(), WgslError> {
+        self.read().get_shader_into(path, output)
+    }
+
+    pub fn try_get_shader(&self, path: impl Into<PathBuf>) -> Result<Option<String>, WgslError> {
+        self.read().try_get_shader(path)
+    }
+
+    pub fn pragmas(&self, path: impl Into<PathBuf>) -> Result<HashMap<String, String>, WgslError> {
+        self.read().pragmas(path)
+    }
+
+    pub fn set_global_i64(&self, key: &str, value: i64) {
+        self.write().set_global_i64(key, value);
+    }
+
+    pub fn set_global_u64(&self, key: &str, value: u64) {
+        self.write().set_global_u64(key, value);
+    }
+
+    pub fn define_flag(&self, name: &str, bit: u32) -> Result<(), WgslError> {
+        self.write().define_flag(name, bit)
+    }
+
+    pub fn set_global_f64(&self, key: &str, value: f64) {
+        self.write().set_global_f64(key, value);
+    }
+
+    pub fn set_global_bool(&self, key: &str, value: bool) {
+        self.write().set_global_bool(key, value);
+    }
+
+    pub fn set_global_list(&self, key: &str, value: Vec<WgslLiteral>) {
+        self.write().set_global_list(key, value);
+    }
+
+    pub fn set_global_str(&self, key: &str, value: &str) {
+        self.write().set_global_str(key, value);
+    }
+
+    /// Same as [`WgslWorkspace::set_globals_from`], renders see either none
+    /// or all of the values.
+    #[cfg(feature = "serde")]
+    pub fn set_globals_from<T: serde::Serialize + ?Sized>(
+        &self,
+        prefix: &str,
+        value: &T,
+    ) -> Result<(), WgslError> {
+        self.write().set_globals_from(prefix, value)
+    }
+
+    /// Same as [`WgslWorkspace::load_defines`], renders never see only part
+    /// of the file.
+    pub fn load_defines(&self, source: &str) -> Result<(), WgslError> {
+        self.write().load_defines(source)
+    }
+
+    pub fn set_features(&self, features: &[&str]) {
+        self.write().set_features(features);
+    }
+
+    pub fn register_function(&self, name: &str, function: WgslFunction) {
+        self.write().register_function(name, function);
+    }
+
+    pub fn set_int_overflow(&self, int_overflow: IntOverflow) {
+        self.write().set_int_overflow(int_overflow);
+    }
+
+    pub fn set_max_unroll(&self, limit: usize) {
+        self.write().set_max_unroll(limit);
+    }
+
+    pub fn set_max_variants(&self, limit: usize) {
+        self.write().set_max_variants(limit);
+    }
+
+    pub fn clear_cache(&self) {
+        self.write().clear_cache();
+    }
+}
+
+impl From<WgslWorkspace> for SyncWgslWorkspace {
+    fn from(workspace: WgslWorkspace) -> Self {
+        Self::new(workspace)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, thread};
+
+    use super::*;
+
+    #[test]
+    fn renders_while_a_global_is_updated() {
+        let workspace =
+            WgslWorkspace::from_memory("", &[("a.wgsl", "//: const A\n//: const_expr B A * 2")])
+                .unwrap();
+
+        let workspace = Arc::new(SyncWgslWorkspace::new(workspace));
+        workspace.set_global_i64("A", 0);
+
+        let readers = (0..4)
+            .map(|_| {
+                let workspace = Arc::clone(&workspace);
+
+                thread::spawn(move || {
+                    for _ in 0..200 {
+                        let shader = workspace.get_shader("a.wgsl").unwrap();
+
+                        // Both constants come from the same value of `A`
+                        let a: i64 = shader["const A = ".len()..shader.find(';').unwrap()]
+                            .parse()
+                            .unwrap();
+                        assert_eq!(shader, format!("const A = {a};\nconst B = {};\n", a * 2));
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+
+        for a in 1..=200 {
+            workspace.set_global_i64("A", a);
+        }
+
+        for reader in readers {
+            reader.join().unwrap();
+        }
+
+        assert_eq!(
+            workspace.get_shader("a.wgsl").unwrap(),
+            "const A = 200;\nconst B = 400;\n"
+        );
+    }
+}
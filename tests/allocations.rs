@@ -0,0 +1,37 @@
+mod counting;
+
+use counting::{allocations, CountingAllocator};
+use wgsl_plus::WgslWorkspace;
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+#[test]
+fn long_lines_are_copied_once() {
+    let render = |size: usize| {
+        let line = "let a = b(c);".repeat(size / 13);
+        let source = format!("//: const A\n{line}\n//: const A");
+
+        allocations(|| {
+            let mut workspace = WgslWorkspace::from_memory("", &[("a.wgsl", &source)]).unwrap();
+            workspace.set_global_i64("A", 1);
+
+            let shader = workspace.get_shader("a.wgsl").unwrap();
+            assert_eq!(shader, format!("const A = 1;\n{line}\nconst A = 1;\n"));
+        })
+    };
+
+    // A line ten times as long is copied into buffers that grow by
+    // doubling, which only takes a few more allocations
+    let short = render(400_000);
+    let long = render(4_000_000);
+    assert!(long <= short + 20, "{long} > {short} + 20");
+
+    let line = "${A}+".repeat(200_000);
+    let mut workspace = WgslWorkspace::from_memory("", &[("a.wgsl", &line)]).unwrap();
+    workspace.set_global_i64("A", 1);
+    assert_eq!(
+        workspace.get_shader("a.wgsl").unwrap(),
+        format!("{}\n", "1+".repeat(200_000))
+    );
+}